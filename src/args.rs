@@ -1,28 +1,363 @@
-use clap::Parser;
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    collector::IngestOptions,
+    filter::{Side, TimeRef},
+    heroes::{HeroPick, HeroRef},
+    service::QueryParameter,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Storage {
+    Clickhouse,
+    /// keep everything in memory, nothing survives a restart
+    Memory,
+}
 
 #[derive(Parser)]
 pub struct Args {
-    #[arg(long, default_value = "http://localhost:8123")]
+    #[arg(long, global = true, value_enum, default_value_t = Storage::Clickhouse)]
+    pub storage: Storage,
+    #[arg(long, global = true, default_value = "http://localhost:8123")]
     pub clickhouse_server: String,
-    #[arg(long)]
-    #[arg(long, default_value = "dota2")]
+    #[arg(long, global = true, default_value = "dota2")]
     pub clickhouse_database: String,
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub clickhouse_user: Option<String>,
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub clickhouse_password: Option<String>,
+    /// json file overriding the embedded hero registry, reloaded by serve on SIGHUP
+    #[arg(long, global = true)]
+    pub heroes: Option<String>,
+    /// json file overriding the embedded patch list, reloaded by serve on SIGHUP
+    #[arg(long, global = true)]
+    pub patches: Option<String>,
 
-    #[arg(long, default_value_t = 6400)]
-    pub interval: u64,
-    #[arg(long, default_value_t = 1000)]
-    pub batch: usize,
-    #[arg(long, default_value = "./collected.json")]
-    pub collected: String,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// serve queries and collect matches in one process
+    Run {
+        #[command(flatten)]
+        serve: ServeArgs,
+        #[command(flatten)]
+        collect: CollectArgs,
+    },
+    /// serve queries only
+    Serve(ServeArgs),
+    /// collect matches only
+    Collect(CollectArgs),
+    /// find matches without the http server
+    Query(QueryArgs),
+    /// find matches an account played in
+    Player(PlayerArgs),
+    /// dump drafts as json lines
+    Export(ExportArgs),
+    /// load drafts from json lines
+    Import(ImportArgs),
+    /// check stored data and collector state for inconsistencies
+    Verify(VerifyArgs),
+    /// print a summary of stored data
+    Stats,
+    /// train a win probability model from stored drafts
+    Train(TrainArgs),
+    /// count drafts stored before hero synergy and counters were maintained
+    ///
+    /// run it once after upgrading, collect, run and import refuse to store drafts until
+    /// it has completed, an interrupted backfill can be run again
+    BackfillPairs,
+}
 
+#[derive(ClapArgs)]
+pub struct ServeArgs {
     #[arg(long, default_value = "localhost")]
     pub addr: String,
     #[arg(long, default_value_t = 8888)]
     pub port: u16,
+    /// model file written by train, enables predictions
+    #[arg(long)]
+    pub model: Option<String>,
+}
+
+#[derive(ClapArgs)]
+pub struct CollectArgs {
+    /// initial interval between two requests of the same key in milliseconds,
+    /// adjusted between min_interval and max_interval according to api responses
+    #[arg(long, default_value_t = 6400)]
+    pub interval: u64,
+    /// shortest interval between two requests of the same key in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    pub min_interval: u64,
+    /// longest interval between two requests of the same key in milliseconds
+    #[arg(long, default_value_t = 60000)]
+    pub max_interval: u64,
+    #[arg(long, default_value_t = 1000)]
+    pub batch: usize,
+    /// number of collectors running concurrently on past ranges
+    #[arg(long, default_value_t = 3)]
+    pub past_collectors: usize,
+    /// collector state lives in the database, this optional file is imported when the
+    /// database has no state yet and is kept updated as a fallback copy
+    #[arg(long)]
+    pub collected: Option<String>,
+
+    /// only store matches of these game modes, the defaults keep regular draft modes
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 2, 3, 4, 16, 22])]
+    pub game_modes: Vec<u8>,
+    /// store matches of any game mode
+    #[arg(long, conflicts_with = "game_modes")]
+    pub any_game_mode: bool,
+    /// only store matches of these lobby types, the defaults keep lobbies played by humans
+    #[arg(long, value_delimiter = ',', default_values_t = [0, 2, 5, 6, 7, 9])]
+    pub lobby_types: Vec<u8>,
+    /// store matches of any lobby type
+    #[arg(long, conflicts_with = "lobby_types")]
+    pub any_lobby_type: bool,
+    /// skip matches shorter than this many seconds
+    #[arg(long, default_value_t = 0)]
+    pub min_duration: u64,
+    /// skip matches with a player counted as abandoned
+    #[arg(long)]
+    pub exclude_abandons: bool,
+    /// also store hero, facet, slot, account and leaver status of every player
+    #[arg(long)]
+    pub store_players: bool,
+
+    /// a file containing one key per line, lines starting with # are ignored
+    #[arg(long)]
+    pub key_file: Option<String>,
+
+    /// steam api keys
+    pub keys: Vec<String>,
+}
+
+impl CollectArgs {
+    // keys from command line followed by keys from key file
+    pub fn keys(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = self.keys.clone();
+        if let Some(path) = &self.key_file {
+            let content = std::fs::read_to_string(path)?;
+            keys.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(ToString::to_string),
+            );
+        }
+        Ok(keys)
+    }
+
+    pub fn ingest_options(&self) -> IngestOptions {
+        // empty for any
+        let allowed = |any: bool, values: &[u8]| if any { vec![] } else { values.to_vec() };
+        IngestOptions {
+            game_modes: allowed(self.any_game_mode, &self.game_modes),
+            lobby_types: allowed(self.any_lobby_type, &self.lobby_types),
+            min_duration: self.min_duration,
+            exclude_abandons: self.exclude_abandons,
+            players: self.store_players,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(ClapArgs)]
+pub struct QueryArgs {
+    /// comma separated hero ids or names, hero:facet to require a facet
+    #[arg(long, value_delimiter = ',')]
+    pub team1: Vec<String>,
+    /// comma separated hero ids or names, hero:facet to require a facet
+    #[arg(long, value_delimiter = ',')]
+    pub team2: Vec<String>,
+    /// heroes team1 must not have
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_team1: Vec<String>,
+    /// heroes team2 must not have
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_team2: Vec<String>,
+    /// heroes not picked by either team
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_any: Vec<String>,
+    /// side team1 plays on
+    #[arg(long, value_enum, default_value_t = Side::Any)]
+    pub side: Side,
+    /// unix timestamp or YYYY-MM-DD, only matches started at or after it
+    #[arg(long)]
+    pub since: Option<String>,
+    /// unix timestamp or YYYY-MM-DD, only matches started before it
+    #[arg(long)]
+    pub until: Option<String>,
+    #[arg(long)]
+    pub min_match_id: Option<u64>,
+    #[arg(long)]
+    pub max_match_id: Option<u64>,
+    /// only matches played on this patch, e.g. 7.36
+    #[arg(long)]
+    pub patch: Option<String>,
+    /// comma separated game mode ids, empty for any
+    #[arg(long, value_delimiter = ',')]
+    pub game_modes: Vec<u8>,
+    /// comma separated lobby type ids, empty for any
+    #[arg(long, value_delimiter = ',')]
+    pub lobby_types: Vec<u8>,
+    #[arg(long, default_value_t = 10)]
+    pub count: usize,
+    #[arg(long, default_value_t = 0)]
+    pub offset: usize,
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    pub format: Format,
+}
+
+// hero:facet requires a facet
+fn parse_pick(name: &str) -> anyhow::Result<HeroPick> {
+    match name.rsplit_once(':') {
+        Some((hero, facet)) => Ok(HeroPick::Faceted {
+            hero: HeroRef::Name(hero.to_string()),
+            facet: facet
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid facet in {}", name))?,
+        }),
+        None => Ok(HeroPick::Hero(HeroRef::Name(name.to_string()))),
+    }
+}
+
+impl QueryArgs {
+    // same parameters the http service takes, so both resolve them the same way
+    pub fn parameter(&self) -> anyhow::Result<QueryParameter> {
+        let picks = |names: &[String]| -> anyhow::Result<Vec<_>> {
+            names.iter().map(|name| parse_pick(name)).collect()
+        };
+        let heroes = |names: &[String]| names.iter().cloned().map(HeroRef::Name).collect();
+        Ok(QueryParameter {
+            team1: picks(&self.team1)?,
+            team2: picks(&self.team2)?,
+            exclude_team1: heroes(&self.exclude_team1),
+            exclude_team2: heroes(&self.exclude_team2),
+            exclude_any: heroes(&self.exclude_any),
+            side: self.side,
+            since: self.since.clone().map(TimeRef::Date),
+            until: self.until.clone().map(TimeRef::Date),
+            min_match_id: self.min_match_id,
+            max_match_id: self.max_match_id,
+            patch: self.patch.clone(),
+            game_modes: self.game_modes.clone(),
+            lobby_types: self.lobby_types.clone(),
+            count: self.count,
+            offset: self.offset,
+        })
+    }
+}
+
+#[derive(ClapArgs)]
+pub struct PlayerArgs {
+    /// steam account id, the 32 bit one
+    pub account_id: u32,
+    #[arg(long, default_value_t = 10)]
+    pub count: usize,
+    #[arg(long, default_value_t = 0)]
+    pub offset: usize,
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    pub format: Format,
+}
+
+#[derive(ClapArgs)]
+pub struct ExportArgs {
+    /// output file, - for stdout
+    #[arg(long, default_value = "-")]
+    pub output: String,
+    /// only export matches with a larger match id
+    #[arg(long, default_value_t = 0)]
+    pub after: u64,
+}
+
+#[derive(ClapArgs)]
+pub struct ImportArgs {
+    /// input file, - for stdin
+    #[arg(long, default_value = "-")]
+    pub input: String,
+    #[arg(long, default_value_t = 10000)]
+    pub batch: usize,
+}
+
+#[derive(ClapArgs)]
+pub struct VerifyArgs {
+    /// local copy of collector state to compare against the database
+    #[arg(long)]
+    pub collected: Option<String>,
+}
+
+#[derive(ClapArgs)]
+pub struct TrainArgs {
+    #[arg(long, default_value = "./model.json")]
+    pub output: String,
+    /// only train on matches with a larger match id
+    #[arg(long, default_value_t = 0)]
+    pub after: u64,
+    #[arg(long, default_value_t = 10)]
+    pub epochs: usize,
+    #[arg(long, default_value_t = 0.01)]
+    pub learning_rate: f64,
+    #[arg(long, default_value_t = 1e-6)]
+    pub l2: f64,
+    /// also learn weights of ally and enemy hero pairs
+    #[arg(long)]
+    pub pairs: bool,
+    /// fraction of the latest matches held out for evaluation
+    #[arg(long, default_value_t = 0.1)]
+    pub holdout: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(args: &[&str]) -> CollectArgs {
+        let args = ["dota2-match-finder", "collect"].iter().chain(args);
+        match Args::try_parse_from(args).unwrap().command {
+            Command::Collect(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn ingest_filters_leave_keys_alone() {
+        let args = collect(&["--game-modes", "22", "--lobby-types", "0,7", "KEY1", "KEY2"]);
+        assert_eq!(args.keys, ["KEY1", "KEY2"]);
+        let options = args.ingest_options();
+        assert_eq!(options.game_modes, [22]);
+        assert_eq!(options.lobby_types, [0, 7]);
+
+        let args = collect(&["--any-game-mode", "--any-lobby-type", "KEY1"]);
+        assert_eq!(args.keys, ["KEY1"]);
+        let options = args.ingest_options();
+        assert!(options.game_modes.is_empty());
+        assert!(options.lobby_types.is_empty());
+
+        let options = collect(&["KEY1"]).ingest_options();
+        assert_eq!(options.game_modes, [1, 2, 3, 4, 16, 22]);
+        assert_eq!(options.lobby_types, [0, 2, 5, 6, 7, 9]);
+    }
 
-    pub key: String,
+    #[test]
+    fn any_conflicts_with_explicit_values() {
+        let args = [
+            "dota2-match-finder",
+            "collect",
+            "--any-game-mode",
+            "--game-modes",
+            "22",
+        ];
+        assert!(Args::try_parse_from(args).is_err());
+    }
 }
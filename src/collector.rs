@@ -1,17 +1,80 @@
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 
 use backon::{ExponentialBuilder, Retryable};
-use kez::{dota2::Match, Client};
+use kez::{
+    dota2::{LeaveStatus, Match},
+    Client,
+};
 
-use crate::dota2::MatchDraft;
+use crate::dota2::{MatchDraft, MatchPlayer, RejectedMatch};
+
+// everything collected from a range of matches
+#[derive(Debug, Clone, Default)]
+pub struct Collected {
+    pub drafts: Vec<MatchDraft>,
+    // empty unless players are ingested
+    pub players: Vec<MatchPlayer>,
+    // matches failing validation
+    pub rejected: Vec<RejectedMatch>,
+}
+
+impl Collected {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            drafts: Vec::with_capacity(capacity),
+            players: vec![],
+            rejected: vec![],
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum CollectResult {
     Normal,
     Yield,
     Decel,
-    Save(Range<u64>, Vec<MatchDraft>),
-    Completed(Range<u64>, Vec<MatchDraft>),
+    // the request never got an answer, says nothing about the key
+    Retry,
+    Save(Range<u64>, Collected),
+    Completed(Range<u64>, Collected),
+}
+
+// what the collector stores, matches not accepted are dropped at ingest
+#[derive(Debug, Clone, Default)]
+pub struct IngestOptions {
+    // allowed game modes and lobby types, empty for any
+    pub game_modes: Vec<u8>,
+    pub lobby_types: Vec<u8>,
+    // seconds
+    pub min_duration: u64,
+    // drop matches with a player counted as abandoned
+    pub exclude_abandons: bool,
+    // also store per-player details of every draft
+    pub players: bool,
+}
+
+impl IngestOptions {
+    fn abandoned(status: LeaveStatus) -> bool {
+        matches!(
+            status,
+            LeaveStatus::DisconnectedTooLong
+                | LeaveStatus::Abandoned
+                | LeaveStatus::AwayFromKeyboard
+        )
+    }
+
+    pub fn accept(&self, mat: &Match) -> bool {
+        let game_mode = u8::from(mat.mode);
+        let lobby_type = u8::from(mat.lobby_type);
+        (self.game_modes.is_empty() || self.game_modes.contains(&game_mode))
+            && (self.lobby_types.is_empty() || self.lobby_types.contains(&lobby_type))
+            && mat.duration.as_secs() >= self.min_duration
+            && !(self.exclude_abandons
+                && mat
+                    .players
+                    .iter()
+                    .any(|player| player.leave_status.is_some_and(Self::abandoned)))
+    }
 }
 
 pub struct Collector {
@@ -19,41 +82,69 @@ pub struct Collector {
     cur: Range<u64>,
     // currently cached range
     cached: Range<u64>,
-    // use Vec<Vec> instead of HashMap<NonZeroU8, Vec> for better performance
-    cache: Vec<MatchDraft>,
+    cache: Collected,
     batch: usize,
+    ingest: Arc<IngestOptions>,
 }
 
 impl Collector {
-    pub fn new(range: Range<u64>, batch: usize) -> Self {
+    pub fn new(range: Range<u64>, batch: usize, ingest: Arc<IngestOptions>) -> Self {
         let Range { start, end } = range;
         let cur = range.clone();
         let cached = range.start..range.start;
-        let cache = Vec::with_capacity(batch + 100);
+        let cache = Collected::with_capacity(batch + 100);
         log::info!("Start collecting matches in [{}, {})", start, end);
         Self {
             cur,
             cache,
             batch,
             cached,
+            ingest,
         }
     }
 
     fn process(&mut self, matches: Vec<Match>) -> CollectResult {
         let start = self.cur.start;
-        self.cache.extend(
-            matches
-                .iter()
-                .filter(|&mat| self.cur.contains(&u64::from(mat.match_seq_num)))
-                .map(Into::into),
-        );
+        let mut skipped = 0;
+        for mat in &matches {
+            if !self.cur.contains(&u64::from(mat.match_seq_num)) {
+                continue;
+            }
+            if !self.ingest.accept(mat) {
+                skipped += 1;
+                continue;
+            }
+            match MatchDraft::try_from(mat) {
+                Ok(draft) => {
+                    if self.ingest.players {
+                        self.cache.players.extend(MatchPlayer::from_match(mat));
+                    }
+                    self.cache.drafts.push(draft);
+                }
+                Err(reason) => {
+                    log::debug!("Rejected match {}: {}", u64::from(mat.match_id), reason);
+                    self.cache.rejected.push(RejectedMatch::new(mat, reason));
+                }
+            }
+        }
 
         // in case the result is empty, we start the next iteration from start+1
-        let end = matches.iter().fold(start + 1, |init, mat| {
-            std::cmp::max(init, u64::from(mat.match_seq_num) + 1)
-        });
+        // matches past the end belong to another collector, so the cached range must
+        // not reach beyond it either
+        let end = matches
+            .iter()
+            .fold(start + 1, |init, mat| {
+                std::cmp::max(init, u64::from(mat.match_seq_num) + 1)
+            })
+            .min(self.cur.end);
         let count = matches.len();
-        log::debug!("Collected {} matches in [{}, {})", count, start, end);
+        log::debug!(
+            "Collected {} matches in [{}, {}), skipped {}",
+            count,
+            start,
+            end,
+            skipped
+        );
 
         self.cur.start = end;
         self.cached.end = end;
@@ -65,22 +156,32 @@ impl Collector {
         if self.cur.is_empty() {
             let range = self.cur.start..self.cur.start;
             let range = std::mem::replace(&mut self.cached, range);
-            let masks = vec![];
-            let masks = std::mem::replace(&mut self.cache, masks);
-            return CollectResult::Completed(range, masks);
+            let cache = std::mem::take(&mut self.cache);
+            return CollectResult::Completed(range, cache);
         }
 
-        if self.cache.len() >= self.batch {
+        if self.cache.drafts.len() >= self.batch {
             let range = self.cur.start..self.cur.start;
             let range = std::mem::replace(&mut self.cached, range);
-            let masks = Vec::with_capacity(self.batch + 100);
-            let masks = std::mem::replace(&mut self.cache, masks);
-            return CollectResult::Save(range, masks);
+            let cache = Collected::with_capacity(self.batch + 100);
+            let cache = std::mem::replace(&mut self.cache, cache);
+            return CollectResult::Save(range, cache);
         }
 
         CollectResult::Normal
     }
 
+    // hand out cached matches before they reach a full batch
+    pub fn flush(&mut self) -> Option<CollectResult> {
+        if self.cached.is_empty() {
+            return None;
+        }
+        let range = self.cur.start..self.cur.start;
+        let range = std::mem::replace(&mut self.cached, range);
+        let cache = std::mem::take(&mut self.cache);
+        Some(CollectResult::Save(range, cache))
+    }
+
     pub async fn step(&mut self, client: &Client) -> anyhow::Result<CollectResult> {
         let start = self.cur.start;
         let result = { || async { client.history(start, 100).await } }
@@ -102,7 +203,7 @@ impl Collector {
             }
             Err(kez::Error::ReqwestError(error)) => {
                 log::warn!("ConnectionError({}): {}", start, error.without_url());
-                Ok(CollectResult::Normal)
+                Ok(CollectResult::Retry)
             }
             Err(error) => {
                 log::warn!("RequestError({}): {}", start, error);
@@ -111,3 +212,78 @@ impl Collector {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::dota2::test_match;
+
+    fn all_pick() -> Match {
+        let radiant = [(1, 1), (2, 1), (3, 1), (4, 1), (5, 1)];
+        let dire = [(6, 1), (7, 1), (8, 1), (9, 1), (10, 1)];
+        test_match(&radiant, &dire)
+    }
+
+    #[test]
+    fn empty_lists_accept_any_mode_and_lobby() {
+        let mut mat = all_pick();
+        mat.mode = 18.into();
+        mat.lobby_type = 4.into();
+        assert!(IngestOptions::default().accept(&mat));
+    }
+
+    #[test]
+    fn modes_and_lobbies_are_restricted() {
+        let options = IngestOptions {
+            game_modes: vec![1, 22],
+            lobby_types: vec![0, 7],
+            ..Default::default()
+        };
+        assert!(options.accept(&all_pick()));
+
+        let mut mat = all_pick();
+        mat.mode = 18.into();
+        assert!(!options.accept(&mat));
+
+        let mut mat = all_pick();
+        mat.lobby_type = 4.into();
+        assert!(!options.accept(&mat));
+    }
+
+    #[test]
+    fn short_matches_are_dropped() {
+        let options = IngestOptions {
+            min_duration: 900,
+            ..Default::default()
+        };
+        let mut mat = all_pick();
+        mat.duration = Duration::from_secs(900);
+        assert!(options.accept(&mat));
+        mat.duration = Duration::from_secs(899);
+        assert!(!options.accept(&mat));
+    }
+
+    #[test]
+    fn only_abandons_are_excluded() {
+        let options = IngestOptions {
+            exclude_abandons: true,
+            ..Default::default()
+        };
+        let mut mat = all_pick();
+        mat.players[7].leave_status = Some(LeaveStatus::Disconnected);
+        assert!(options.accept(&mat));
+        mat.players[7].leave_status = Some(LeaveStatus::NeverConnected);
+        assert!(options.accept(&mat));
+        for status in [
+            LeaveStatus::DisconnectedTooLong,
+            LeaveStatus::Abandoned,
+            LeaveStatus::AwayFromKeyboard,
+        ] {
+            mat.players[7].leave_status = Some(status);
+            assert!(IngestOptions::default().accept(&mat), "{:?}", status);
+            assert!(!options.accept(&mat), "{:?}", status);
+        }
+    }
+}
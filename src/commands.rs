@@ -0,0 +1,401 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use itertools::Itertools;
+
+use crate::{
+    args::{ExportArgs, Format, ImportArgs, PlayerArgs, QueryArgs, TrainArgs, VerifyArgs},
+    dota2::{MatchDraft, PlayerMatch},
+    filter::DraftFilter,
+    heroes::HeroRegistry,
+    model::{Evaluation, TrainOptions, Trainer},
+    patches::PatchList,
+    scheduler::CollectorState,
+    service::{MatchEntry, PlayerEntry},
+    store::{ensure_pairs_backfilled, DraftStore},
+};
+
+const PAGE: usize = 10000;
+
+fn names(heroes: &HeroRegistry, ids: &[u8]) -> String {
+    ids.iter().map(|&id| heroes.name(id)).join(", ")
+}
+
+fn print_table(heroes: &HeroRegistry, filter: &DraftFilter, drafts: &[MatchDraft]) {
+    println!(
+        "{:<12} {:<8} {:<8} {:<12} {:<8} {:<60} dire",
+        "match_id", "team1", "winner", "start_time", "duration", "radiant"
+    );
+    for draft in drafts {
+        let winner = match (draft.has_outcome(), draft.radiant_win) {
+            (false, _) => "unknown",
+            (true, true) => "radiant",
+            (true, false) => "dire",
+        };
+        println!(
+            "{:<12} {:<8} {:<8} {:<12} {:<8} {:<60} {}",
+            draft.match_id,
+            filter.team1_side(draft).as_str(),
+            winner,
+            draft.start_time,
+            draft.duration,
+            names(heroes, &draft.radiant),
+            names(heroes, &draft.dire)
+        );
+    }
+}
+
+fn print_csv(filter: &DraftFilter, drafts: &[MatchDraft]) {
+    println!("match_id,radiant_win,start_time,duration,game_mode,lobby_type,cluster,radiant,dire,radiant_facets,dire_facets,team1_side");
+    for draft in drafts {
+        // left empty for drafts without outcome
+        let radiant_win = if draft.has_outcome() {
+            draft.radiant_win.to_string()
+        } else {
+            String::new()
+        };
+        println!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            draft.match_id,
+            radiant_win,
+            draft.start_time,
+            draft.duration,
+            draft.game_mode,
+            draft.lobby_type,
+            draft.cluster,
+            draft.radiant.iter().join(" "),
+            draft.dire.iter().join(" "),
+            draft.radiant_facets.iter().join(" "),
+            draft.dire_facets.iter().join(" "),
+            filter.team1_side(draft).as_str()
+        );
+    }
+}
+
+pub async fn query(
+    database: &dyn DraftStore,
+    heroes: &HeroRegistry,
+    patches: &PatchList,
+    args: QueryArgs,
+) -> anyhow::Result<()> {
+    let parameter = args.parameter()?;
+    let filter = parameter.filter(heroes, patches)?;
+    let drafts = database
+        .query_matches(&filter, parameter.count, parameter.offset)
+        .await?;
+    match args.format {
+        Format::Table => print_table(heroes, &filter, &drafts),
+        Format::Json => {
+            // same entries as the http service, with team1_side
+            let entries: Vec<_> = drafts
+                .into_iter()
+                .map(|draft| MatchEntry::new(draft, heroes, &filter))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&entries)?)
+        }
+        Format::Csv => print_csv(&filter, &drafts),
+    }
+    Ok(())
+}
+
+pub async fn player(
+    database: &dyn DraftStore,
+    heroes: &HeroRegistry,
+    args: PlayerArgs,
+) -> anyhow::Result<()> {
+    let played = database
+        .query_player(args.account_id, args.count, args.offset)
+        .await?;
+    let side = |played: &PlayerMatch| {
+        if played.player.is_radiant() {
+            "radiant"
+        } else {
+            "dire"
+        }
+    };
+    match args.format {
+        Format::Table => {
+            println!(
+                "{:<12} {:<8} {:<6} {:<20} {:<12} {:<60} dire",
+                "match_id", "side", "result", "hero", "start_time", "radiant"
+            );
+            for played in &played {
+                let result = if played.won() { "won" } else { "lost" };
+                println!(
+                    "{:<12} {:<8} {:<6} {:<20} {:<12} {:<60} {}",
+                    played.draft.match_id,
+                    side(played),
+                    result,
+                    heroes.name(played.player.hero),
+                    played.draft.start_time,
+                    names(heroes, &played.draft.radiant),
+                    names(heroes, &played.draft.dire)
+                );
+            }
+        }
+        Format::Json => {
+            // same entries as the http service, with side and result
+            let entries: Vec<_> = played
+                .into_iter()
+                .map(|played| PlayerEntry::new(played, heroes))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&entries)?)
+        }
+        Format::Csv => {
+            println!("match_id,side,won,hero,facet,start_time,duration,game_mode,lobby_type,radiant,dire");
+            for played in &played {
+                println!(
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    played.draft.match_id,
+                    side(played),
+                    played.won(),
+                    played.player.hero,
+                    played.player.facet,
+                    played.draft.start_time,
+                    played.draft.duration,
+                    played.draft.game_mode,
+                    played.draft.lobby_type,
+                    played.draft.radiant.iter().join(" "),
+                    played.draft.dire.iter().join(" ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn export(database: &dyn DraftStore, args: ExportArgs) -> anyhow::Result<()> {
+    let output: Box<dyn Write> = match args.output.as_str() {
+        "-" => Box::new(std::io::stdout()),
+        path => Box::new(std::fs::File::create(path)?),
+    };
+    let mut output = BufWriter::new(output);
+
+    let mut after = args.after;
+    let mut count = 0;
+    loop {
+        let drafts = database.scan_drafts(after, PAGE).await?;
+        let Some(last) = drafts.last() else {
+            break;
+        };
+        after = last.match_id;
+        for draft in &drafts {
+            serde_json::to_writer(&mut output, draft)?;
+            output.write_all(b"\n")?;
+        }
+        count += drafts.len();
+        log::debug!("Exported {} drafts up to match {}", count, after);
+    }
+    output.flush()?;
+    log::info!("Exported {} drafts", count);
+    Ok(())
+}
+
+pub async fn import(database: &dyn DraftStore, args: ImportArgs) -> anyhow::Result<()> {
+    ensure_pairs_backfilled(database).await?;
+    let input: Box<dyn BufRead> = match args.input.as_str() {
+        "-" => Box::new(BufReader::new(std::io::stdin())),
+        path => Box::new(BufReader::new(std::fs::File::open(path)?)),
+    };
+
+    let mut drafts = Vec::with_capacity(args.batch);
+    let mut count = 0;
+    for (idx, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let draft: MatchDraft = serde_json::from_str(&line)
+            .map_err(|err| anyhow::anyhow!("invalid draft on line {}: {}", idx + 1, err))?;
+        drafts.push(draft);
+        if drafts.len() >= args.batch {
+            database.save_match_drafts(&drafts).await?;
+            count += drafts.len();
+            drafts.clear();
+        }
+    }
+    if !drafts.is_empty() {
+        database.save_match_drafts(&drafts).await?;
+        count += drafts.len();
+    }
+    log::info!("Imported {} drafts", count);
+    Ok(())
+}
+
+// problems of collected ranges, they should be sorted and never overlap
+fn check_ranges(collected: &[(u64, u64)]) -> Vec<String> {
+    let mut problems: Vec<_> = collected
+        .iter()
+        .filter(|(start, end)| start > end)
+        .map(|(start, end)| format!("range [{}, {}) is reversed", start, end))
+        .collect();
+    problems.extend(
+        collected
+            .windows(2)
+            .filter(|pair| pair[0].1 >= pair[1].0)
+            .map(|pair| {
+                format!(
+                    "range [{}, {}) is not before [{}, {})",
+                    pair[0].0, pair[0].1, pair[1].0, pair[1].1
+                )
+            }),
+    );
+    problems
+}
+
+pub async fn verify(database: &dyn DraftStore, args: VerifyArgs) -> anyhow::Result<()> {
+    let mut problems = vec![];
+
+    let stored = database.load_collected().await?;
+    match &stored {
+        Some(collected) => problems.extend(check_ranges(collected)),
+        None => println!("no collector state in database"),
+    }
+
+    // the local copy is optional
+    if let Some(path) = &args.collected {
+        match CollectorState::load(path) {
+            Ok(Some(state)) => {
+                problems.extend(check_ranges(state.collected()));
+                if stored
+                    .as_deref()
+                    .is_some_and(|stored| stored != state.collected())
+                {
+                    println!(
+                        "collector state in {} differs from database, the database takes precedence",
+                        path
+                    );
+                }
+            }
+            Ok(None) => println!("no collector state file at {}", path),
+            Err(err) => problems.push(err.to_string()),
+        }
+    }
+
+    let summary = database.summary().await?;
+    if summary.drafts > summary.matches {
+        problems.push(format!(
+            "{} duplicated drafts",
+            summary.drafts - summary.matches
+        ));
+    }
+
+    for problem in &problems {
+        println!("problem: {}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problem(s) found", problems.len());
+    }
+    println!("ok");
+    Ok(())
+}
+
+pub async fn stats(database: &dyn DraftStore) -> anyhow::Result<()> {
+    let summary = database.summary().await?;
+    println!("drafts:        {}", summary.drafts);
+    println!("matches:       {}", summary.matches);
+    println!(
+        "match ids:     [{}, {}]",
+        summary.min_match_id, summary.max_match_id
+    );
+
+    let collected = database.load_collected().await?.unwrap_or_default();
+    let total: u64 = collected
+        .iter()
+        .map(|(start, end)| end.saturating_sub(*start))
+        .sum();
+    println!("ranges:        {}", collected.len());
+    println!("seq nums:      {}", total);
+    if let (Some(first), Some(last)) = (collected.first(), collected.last()) {
+        println!("collected:     [{}, {})", first.0, last.1);
+    }
+
+    let rejected = database.rejected_counts().await?;
+    let total: u64 = rejected.iter().map(|rejected| rejected.count).sum();
+    println!("rejected:      {}", total);
+    for rejected in &rejected {
+        println!("  {:<13}{}", rejected.reason, rejected.count);
+    }
+    Ok(())
+}
+
+pub async fn backfill_pairs(database: &dyn DraftStore) -> anyhow::Result<()> {
+    database.backfill_pairs().await?;
+    println!("ok");
+    Ok(())
+}
+
+// drafts a model can learn from
+fn trainable(draft: &MatchDraft) -> bool {
+    // drafts stored before validation may be padded with 0, and drafts stored before
+    // outcomes have none to learn from
+    draft.has_outcome() && !draft.radiant.contains(&0) && !draft.dire.contains(&0)
+}
+
+pub async fn train(database: &dyn DraftStore, args: TrainArgs) -> anyhow::Result<()> {
+    if !(0.0..1.0).contains(&args.holdout) {
+        anyhow::bail!("holdout should be in [0, 1)");
+    }
+    if args.epochs == 0 {
+        anyhow::bail!("at least one epoch is needed");
+    }
+
+    // drafts are streamed in match id order on every epoch, the drafts after the split
+    // are held out, there are gaps in match ids so the split is taken by count
+    let Some(split) = database
+        .match_id_quantile(args.after, 1.0 - args.holdout)
+        .await?
+    else {
+        anyhow::bail!("no drafts to train on");
+    };
+
+    let mut trainer = Trainer::new(TrainOptions {
+        learning_rate: args.learning_rate,
+        l2: args.l2,
+        pairs: args.pairs,
+    });
+    let mut holdout = Evaluation::default();
+    for epoch in 1..=args.epochs {
+        // training drafts are evaluated right before they are fitted
+        let mut train = Evaluation::default();
+        holdout = Evaluation::default();
+        let mut after = args.after;
+        loop {
+            let page = database.scan_drafts(after, PAGE).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = last.match_id;
+            let (mut fit, held): (Vec<_>, Vec<_>) = page
+                .into_iter()
+                .filter(trainable)
+                .partition(|draft| draft.match_id <= split);
+            train.add(trainer.model(), &fit);
+            trainer.fit(&mut fit);
+            holdout.add(trainer.model(), &held);
+        }
+        if train.count() == 0 {
+            anyhow::bail!("no drafts to train on");
+        }
+        log::info!(
+            "Epoch {}: train loss {:.4} accuracy {:.4} on {}, holdout loss {:.4} accuracy {:.4} on {}",
+            epoch,
+            train.loss(),
+            train.accuracy(),
+            train.count(),
+            holdout.loss(),
+            holdout.accuracy(),
+            holdout.count()
+        );
+    }
+    println!(
+        "holdout drafts:   {} after match {}",
+        holdout.count(),
+        split
+    );
+    println!("holdout loss:     {:.4}", holdout.loss());
+    println!("holdout accuracy: {:.4}", holdout.accuracy());
+
+    trainer.finish().save(&args.output)?;
+    log::info!("Saved model to {}", args.output);
+    Ok(())
+}
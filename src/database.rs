@@ -1,7 +1,244 @@
-use clickhouse::{error::Error, Client};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use clickhouse::{Client, Row};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dota2::{
+        CollectedRanges, HeroPairStats, HeroStats, MatchDraft, MatchPlayer, MatchupStats,
+        PlayerMatch, Progress, RejectedCount, RejectedMatch, StoreSummary,
+    },
+    filter::{DraftFilter, Side},
+    heroes::HeroFacet,
+    store::DraftStore,
+};
+
+// drafts stored before outcomes were added have no meaningful radiant_win
+const WITH_OUTCOME: &str = "start_time != 0";
+
+// version of the migration creating the hero pair views
+const PAIR_VIEWS: u32 = 10;
+
+// held while migrating or backfilling, creating a table is atomic so only one process
+// gets to create it
+const LOCK_TABLE: &str = "schema_lock";
+const LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    // every statement should be idempotent, a migration interrupted halfway will be
+    // applied again from its first statement on next startup
+    statements: &'static [&'static str],
+}
+
+// every hero with each of its allies, wins from the first hero's perspective
+// drafts without outcome are left out, the condition is WITH_OUTCOME
+macro_rules! synergy_select {
+    () => {
+        "SELECT hero, other, toUInt64(1) AS games, toUInt64(team.2) AS wins
+        FROM drafts
+        ARRAY JOIN [(radiant, if(radiant_win, 1, 0)), (dire, if(radiant_win, 0, 1))] AS team
+        ARRAY JOIN array(untuple(team.1)) AS hero
+        ARRAY JOIN array(untuple(team.1)) AS other
+        WHERE hero != other AND start_time != 0"
+    };
+}
+
+// every hero with each of its enemies, wins from the first hero's perspective
+macro_rules! counters_select {
+    () => {
+        "SELECT hero, other, toUInt64(1) AS games, toUInt64(team.3) AS wins
+        FROM drafts
+        ARRAY JOIN [(radiant, dire, if(radiant_win, 1, 0)), (dire, radiant, if(radiant_win, 0, 1))] AS team
+        ARRAY JOIN array(untuple(team.1)) AS hero
+        ARRAY JOIN array(untuple(team.2)) AS other
+        WHERE start_time != 0"
+    };
+}
 
-use crate::dota2::{MatchDraft, Progress};
+// hero pair tables with the drafts they count
+const PAIR_TABLES: [(&str, &str); 2] = [
+    ("hero_synergy", synergy_select!()),
+    ("hero_counters", counters_select!()),
+];
+
+// migrations are applied in order, append new migrations to the end and never modify
+// the ones already released
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create drafts table",
+        statements: &["CREATE TABLE IF NOT EXISTS drafts (
+            match_id UInt64,
+            radiant Tuple(UInt8, UInt8, UInt8, UInt8, UInt8),
+            dire Tuple(UInt8, UInt8, UInt8, UInt8, UInt8),
+        )
+        ENGINE = MergeTree()
+        ORDER BY match_id
+        PARTITION BY intDiv(match_id, 10000000)
+        PRIMARY KEY match_id;"],
+    },
+    Migration {
+        version: 2,
+        description: "create progress table",
+        statements: &["CREATE TABLE IF NOT EXISTS progress (
+            timestamp UInt64,
+            match_seq_num UInt64,
+        )
+        ENGINE = MergeTree()
+        ORDER BY timestamp
+        PRIMARY KEY timestamp;"],
+    },
+    // existing rows get the column defaults, start_time 0 marks them as without outcome
+    Migration {
+        version: 3,
+        description: "add match outcome and metadata to drafts",
+        statements: &[
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS radiant_win Bool;",
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS start_time UInt64;",
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS duration UInt32;",
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS game_mode UInt8;",
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS lobby_type UInt8;",
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS cluster UInt32;",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "create collector_state table",
+        statements: &["CREATE TABLE IF NOT EXISTS collector_state (
+            timestamp UInt64,
+            collected Array(Tuple(UInt64, UInt64)),
+        )
+        ENGINE = MergeTree()
+        ORDER BY timestamp
+        PRIMARY KEY timestamp;"],
+    },
+    Migration {
+        version: 5,
+        description: "add start_time index to drafts",
+        statements: &[
+            "ALTER TABLE drafts ADD INDEX IF NOT EXISTS start_time_index start_time TYPE minmax GRANULARITY 4;",
+            "ALTER TABLE drafts MATERIALIZE INDEX start_time_index;",
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "create rejected_matches table",
+        statements: &["CREATE TABLE IF NOT EXISTS rejected_matches (
+            match_id UInt64,
+            match_seq_num UInt64,
+            reason LowCardinality(String),
+            timestamp UInt64,
+        )
+        ENGINE = MergeTree()
+        ORDER BY match_id
+        PRIMARY KEY match_id;"],
+    },
+    Migration {
+        version: 7,
+        description: "create match_players table",
+        statements: &["CREATE TABLE IF NOT EXISTS match_players (
+            match_id UInt64,
+            hero UInt8,
+            facet UInt8,
+            player_slot UInt8,
+            account_id Nullable(UInt32),
+            leaver_status UInt8,
+        )
+        ENGINE = MergeTree()
+        ORDER BY (match_id, player_slot)
+        PARTITION BY intDiv(match_id, 10000000);"],
+    },
+    Migration {
+        version: 8,
+        description: "add hero facets to drafts",
+        statements: &[
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS radiant_facets Tuple(UInt8, UInt8, UInt8, UInt8, UInt8);",
+            "ALTER TABLE drafts ADD COLUMN IF NOT EXISTS dire_facets Tuple(UInt8, UInt8, UInt8, UInt8, UInt8);",
+        ],
+    },
+    Migration {
+        version: 9,
+        description: "add account_id index to match_players",
+        statements: &[
+            "ALTER TABLE match_players ADD INDEX IF NOT EXISTS account_id_index account_id TYPE bloom_filter GRANULARITY 4;",
+            "ALTER TABLE match_players MATERIALIZE INDEX account_id_index;",
+        ],
+    },
+    // pairs are counted from both heroes, so (a, b) and (b, a) are both present
+    // the views only count drafts inserted after they are created, drafts stored before
+    // are counted by the backfill-pairs subcommand, nothing is stored until it completes
+    Migration {
+        version: PAIR_VIEWS,
+        description: "create hero synergy and counter views",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS hero_synergy (
+                hero UInt8,
+                other UInt8,
+                games UInt64,
+                wins UInt64,
+            )
+            ENGINE = SummingMergeTree()
+            ORDER BY (hero, other);",
+            "CREATE TABLE IF NOT EXISTS hero_counters (
+                hero UInt8,
+                other UInt8,
+                games UInt64,
+                wins UInt64,
+            )
+            ENGINE = SummingMergeTree()
+            ORDER BY (hero, other);",
+            "CREATE TABLE IF NOT EXISTS pair_backfill (
+                name String,
+                completed_at UInt64,
+            )
+            ENGINE = MergeTree()
+            ORDER BY name;",
+            // there is nothing to backfill in a new database
+            "INSERT INTO pair_backfill (name, completed_at)
+            SELECT name, toUInt64(now()) FROM system.one
+            ARRAY JOIN ['hero_synergy', 'hero_counters'] AS name
+            WHERE (SELECT count() FROM drafts) = 0;",
+            concat!(
+                "CREATE MATERIALIZED VIEW IF NOT EXISTS hero_synergy_view TO hero_synergy AS ",
+                synergy_select!()
+            ),
+            concat!(
+                "CREATE MATERIALIZED VIEW IF NOT EXISTS hero_counters_view TO hero_counters AS ",
+                counters_select!()
+            ),
+        ],
+    },
+];
+
+#[derive(Row, Debug, Clone, Serialize, Deserialize)]
+struct SchemaVersion {
+    version: u32,
+    description: String,
+    applied_at: u64,
+}
+
+// a hero pair table done counting the drafts stored before its view was created
+#[derive(Row, Debug, Clone, Serialize, Deserialize)]
+struct PairBackfill {
+    name: String,
+    completed_at: u64,
+}
+
+#[derive(Row, Debug, Clone, Deserialize)]
+struct PeriodStats {
+    period: u64,
+    matches: u64,
+    team1_wins: u64,
+    team2_wins: u64,
+    win_rate: f64,
+}
 
 pub struct Database {
     database: String,
@@ -15,7 +252,7 @@ impl Database {
         database: &str,
         user: Option<&str>,
         password: Option<&str>,
-    ) -> Result<Self, Error> {
+    ) -> anyhow::Result<Self> {
         let database = database.to_string();
         let client = Client::default().with_url(server);
 
@@ -36,80 +273,424 @@ impl Database {
         let client = client.with_database(&database);
         let table = "drafts".to_string();
 
-        let query = format!(
-            "CREATE TABLE IF NOT EXISTS {}.{} (
-                match_id UInt64,
-                radiant Tuple(UInt8, UInt8, UInt8, UInt8, UInt8),
-                dire Tuple(UInt8, UInt8, UInt8, UInt8, UInt8),
+        Self::migrate(&client).await?;
+
+        Ok(Self {
+            database,
+            client,
+            table,
+        })
+    }
+
+    async fn schema_version(client: &Client) -> anyhow::Result<u32> {
+        // max() of an empty table is 0, which is also the version of a deployment
+        // created before migrations were introduced
+        let current = client
+            .query("SELECT max(version) FROM schema_version")
+            .fetch_one()
+            .await?;
+        Ok(current)
+    }
+
+    // wait for other processes to finish migrating or backfilling
+    async fn lock(client: &Client) -> anyhow::Result<()> {
+        // the table stays empty, its existence is the lock
+        let query = format!("CREATE TABLE {} (locked UInt8) ENGINE = Memory", LOCK_TABLE);
+        let started = Instant::now();
+        loop {
+            let err = match client.query(&query).execute().await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let exists: u8 = client
+                .query(&format!("EXISTS TABLE {}", LOCK_TABLE))
+                .fetch_one()
+                .await?;
+            if exists == 0 {
+                return Err(err.into());
+            }
+            if started.elapsed() > LOCK_TIMEOUT {
+                anyhow::bail!(
+                    "{} has been held for more than {}s, drop the table if no other process is migrating or backfilling",
+                    LOCK_TABLE,
+                    LOCK_TIMEOUT.as_secs()
+                );
+            }
+            log::info!("Waiting for another process holding {}", LOCK_TABLE);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    async fn unlock(client: &Client) -> anyhow::Result<()> {
+        let query = format!("DROP TABLE IF EXISTS {}", LOCK_TABLE);
+        client.query(&query).execute().await?;
+        Ok(())
+    }
+
+    // run f while holding the lock, the lock is released even if f fails
+    async fn locked<T>(
+        client: &Client,
+        f: impl std::future::Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        Self::lock(client).await?;
+        let result = f.await;
+        Self::unlock(client).await?;
+        result
+    }
+
+    async fn migrate(client: &Client) -> anyhow::Result<()> {
+        let query = "CREATE TABLE IF NOT EXISTS schema_version (
+                version UInt32,
+                description String,
+                applied_at UInt64,
             )
             ENGINE = MergeTree()
-            ORDER BY match_id
-            PARTITION BY intDiv(match_id, 10000000)
-            PRIMARY KEY match_id;",
-            &database, &table,
-        );
-        client.query(&query).execute().await?;
+            ORDER BY version
+            PRIMARY KEY version;";
+        client.query(query).execute().await?;
+
+        let latest = MIGRATIONS.last().map_or(0, |mig| mig.version);
+        let current = Self::schema_version(client).await?;
+        if current > latest {
+            anyhow::bail!(
+                "database schema version {} is newer than the latest version {} known to this binary, please upgrade",
+                current,
+                latest
+            );
+        }
+        if current == latest {
+            return Ok(());
+        }
+        Self::locked(client, Self::apply_migrations(client)).await
+    }
+
+    async fn apply_migrations(client: &Client) -> anyhow::Result<()> {
+        // another process may have migrated while we were waiting for the lock
+        let current = Self::schema_version(client).await?;
+        for mig in MIGRATIONS.iter().filter(|mig| mig.version > current) {
+            log::info!("Applying migration {}: {}", mig.version, mig.description);
+            for statement in mig.statements {
+                client.query(statement).execute().await?;
+            }
+            let applied_at = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs();
+            let version = SchemaVersion {
+                version: mig.version,
+                description: mig.description.to_string(),
+                applied_at,
+            };
+            let mut insert = client.insert("schema_version")?;
+            insert.write(&version).await?;
+            insert.end().await?;
+            if mig.version == PAIR_VIEWS && !Self::pairs_pending(client).await?.is_empty() {
+                log::warn!(
+                    "Hero synergy and counters only count new drafts, run backfill-pairs to count drafts stored before, nothing can be stored until then"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // hero pair tables not backfilled yet
+    async fn pairs_pending(client: &Client) -> anyhow::Result<Vec<&'static str>> {
+        let completed: Vec<String> = client
+            .query("SELECT DISTINCT name FROM pair_backfill")
+            .fetch_all()
+            .await?;
+        Ok(PAIR_TABLES
+            .iter()
+            .map(|&(table, _)| table)
+            .filter(|table| !completed.iter().any(|name| name == table))
+            .collect())
+    }
+
+    // every draft is counted into a staging table which then replaces the whole table,
+    // so whatever the views counted so far is dropped instead of counted twice, and an
+    // interrupted backfill can be run again, tables already completed are skipped
+    async fn backfill(&self) -> anyhow::Result<()> {
+        let pending = Self::pairs_pending(&self.client).await?;
+        if pending.is_empty() {
+            anyhow::bail!("hero pairs were already backfilled");
+        }
+
+        for (table, select) in PAIR_TABLES {
+            if !pending.contains(&table) {
+                log::info!("Skipping {}, already backfilled", table);
+                continue;
+            }
+            let staging = format!("{}.{}_backfill", self.database, table);
+            let statements = [
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} AS {}.{}",
+                    staging, self.database, table
+                ),
+                format!("TRUNCATE TABLE {}", staging),
+                format!("INSERT INTO {} {}", staging, select),
+                format!(
+                    "ALTER TABLE {}.{} REPLACE PARTITION tuple() FROM {}",
+                    self.database, table, staging
+                ),
+            ];
+            log::info!("Counting stored drafts for {}", table);
+            for statement in statements {
+                self.client.query(&statement).execute().await?;
+            }
+
+            let completed = PairBackfill {
+                name: table.to_string(),
+                completed_at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs(),
+            };
+            let mut insert = self.client.insert("pair_backfill")?;
+            insert.write(&completed).await?;
+            insert.end().await?;
 
+            let query = format!("DROP TABLE IF EXISTS {}", staging);
+            self.client.query(&query).execute().await?;
+        }
+        Ok(())
+    }
+
+    async fn pair_stats(&self, table: &str, hero: u8) -> anyhow::Result<Vec<HeroPairStats>> {
+        // rows are only summed up on merges, so they are aggregated again here
         let query = format!(
-            "CREATE TABLE IF NOT EXISTS {}.{} (
-                timestamp UInt64,
-                match_seq_num UInt64,
+            "SELECT other, together AS games, won AS wins, if(together = 0, 0, won / together) AS win_rate
+            FROM (
+                SELECT other, sum(games) AS together, sum(wins) AS won
+                FROM {}.{} WHERE hero = {} GROUP BY other
             )
-            ENGINE = MergeTree()
-            ORDER BY timestamp
-            PRIMARY KEY timestamp;",
-            &database, "progress",
+            ORDER BY games DESC, other",
+            self.database, table, hero
         );
-        client.query(&query).execute().await?;
+        Ok(self.client.query(&query).fetch_all().await?)
+    }
 
-        Ok(Self {
-            database,
-            client,
-            table,
-        })
+    // returns conditions of (team1 on radiant, team1 on dire), None if the filter has no heroes
+    fn team_conditions(filter: &DraftFilter) -> Option<(String, String)> {
+        if filter.is_empty() {
+            return None;
+        }
+
+        let side_check = |function: &str, side: &str, heroes: &[u8]| {
+            (!heroes.is_empty()).then(|| {
+                format!(
+                    "{}(bitmapBuild(array(untuple({}))), bitmapBuild([{}]))",
+                    function,
+                    side,
+                    heroes.iter().format(","),
+                )
+            })
+        };
+        let has_all = |side: &str, heroes: &[u8]| side_check("bitmapHasAll", side, heroes);
+        let has_none = |side: &str, heroes: &[u8]| side_check("NOT bitmapHasAny", side, heroes);
+        let has_facets = |side: &str, picks: &[HeroFacet]| {
+            (!picks.is_empty()).then(|| {
+                format!(
+                    "hasAll(arrayZip(array(untuple({0})), array(untuple({0}_facets))), [{1}])",
+                    side,
+                    picks
+                        .iter()
+                        .map(|(hero, facet)| format!("({}, {})", hero, facet))
+                        .join(","),
+                )
+            })
+        };
+        let check = |team1: &str, team2: &str| {
+            let conditions = [
+                has_all(team1, &filter.team1),
+                has_all(team2, &filter.team2),
+                has_facets(team1, &filter.team1_facets),
+                has_facets(team2, &filter.team2_facets),
+                has_none(team1, &filter.exclude_team1),
+                has_none(team2, &filter.exclude_team2),
+                has_none(team1, &filter.exclude_any),
+                has_none(team2, &filter.exclude_any),
+            ];
+            format!("({})", conditions.into_iter().flatten().join(" AND "))
+        };
+
+        // a side team1 must not be on never matches
+        let (radiant, dire) = (check("radiant", "dire"), check("dire", "radiant"));
+        match filter.side {
+            Side::Any => Some((radiant, dire)),
+            Side::Radiant => Some((radiant, "0".to_string())),
+            Side::Dire => Some(("0".to_string(), dire)),
+        }
     }
 
-    pub async fn query_matches(
+    // conditions not depending on the side of team1, each prefixed with AND
+    // match id bounds let clickhouse skip whole partitions
+    fn match_conditions(filter: &DraftFilter) -> String {
+        let mut conditions = String::new();
+        if !filter.game_modes.is_empty() {
+            conditions += &format!(
+                " AND game_mode IN ({})",
+                filter.game_modes.iter().format(",")
+            );
+        }
+        if !filter.lobby_types.is_empty() {
+            conditions += &format!(
+                " AND lobby_type IN ({})",
+                filter.lobby_types.iter().format(",")
+            );
+        }
+        if let Some(id) = filter.min_match_id {
+            conditions += &format!(" AND match_id >= {}", id);
+        }
+        if let Some(id) = filter.max_match_id {
+            conditions += &format!(" AND match_id <= {}", id);
+        }
+        if let Some(since) = filter.since {
+            conditions += &format!(" AND start_time >= {}", since);
+        }
+        if let Some(until) = filter.until {
+            conditions += &format!(" AND start_time < {}", until);
+        }
+        conditions
+    }
+}
+
+#[async_trait]
+impl DraftStore for Database {
+    async fn query_matches(
         &self,
-        team1: &[u8],
-        team2: &[u8],
+        filter: &DraftFilter,
         limit: usize,
         offset: usize,
-    ) -> Result<Vec<MatchDraft>, Error> {
-        let side_check = |side: &str, heroes: &[u8]| {
+    ) -> anyhow::Result<Vec<MatchDraft>> {
+        let Some((cond1, cond2)) = Self::team_conditions(filter) else {
+            return Ok(vec![]);
+        };
+
+        let query = format!(
+            "SELECT ?fields FROM {}.{} WHERE ({} OR {}){} ORDER BY match_id DESC LIMIT {} OFFSET {}",
+            self.database,
+            self.table,
+            cond1,
+            cond2,
+            Self::match_conditions(filter),
+            limit,
+            offset
+        );
+        Ok(self.client.query(&query).fetch_all().await?)
+    }
+
+    async fn query_stats(&self, filter: &DraftFilter) -> anyhow::Result<MatchupStats> {
+        let Some((cond1, cond2)) = Self::team_conditions(filter) else {
+            return Ok(MatchupStats::default());
+        };
+
+        // a match satisfies at most one of the conditions unless team1 and team2 overlap,
+        // in which case team1 is considered radiant
+        let query = format!(
+            "SELECT
+                count() AS matches,
+                countIf(({0} AND radiant_win) OR (NOT {0} AND NOT radiant_win)) AS team1_wins,
+                toUInt64(matches - team1_wins) AS team2_wins,
+                if(matches = 0, 0, team1_wins / matches) AS win_rate
+            FROM {2}.{3} WHERE ({0} OR {1}) AND {WITH_OUTCOME}{4}",
+            cond1,
+            cond2,
+            self.database,
+            self.table,
+            Self::match_conditions(filter)
+        );
+        Ok(self.client.query(&query).fetch_one().await?)
+    }
+
+    async fn query_stats_by_period(
+        &self,
+        filter: &DraftFilter,
+        boundaries: &[u64],
+    ) -> anyhow::Result<Vec<(usize, MatchupStats)>> {
+        let Some((cond1, cond2)) = Self::team_conditions(filter) else {
+            return Ok(vec![]);
+        };
+
+        let period = if boundaries.is_empty() {
+            "toUInt64(0)".to_string()
+        } else {
             format!(
-                "(bitmapHasAll(bitmapBuild(array(untuple({}))), bitmapBuild([{}])))",
-                side,
-                heroes.iter().format(","),
+                "toUInt64(arrayCount(start -> start <= start_time, [{}]))",
+                boundaries.iter().format(",")
             )
         };
+        let query = format!(
+            "SELECT
+                {5} AS period,
+                count() AS matches,
+                countIf(({0} AND radiant_win) OR (NOT {0} AND NOT radiant_win)) AS team1_wins,
+                toUInt64(matches - team1_wins) AS team2_wins,
+                if(matches = 0, 0, team1_wins / matches) AS win_rate
+            FROM {2}.{3} WHERE ({0} OR {1}) AND {WITH_OUTCOME}{4}
+            GROUP BY period ORDER BY period",
+            cond1,
+            cond2,
+            self.database,
+            self.table,
+            Self::match_conditions(filter),
+            period
+        );
+        let rows: Vec<PeriodStats> = self.client.query(&query).fetch_all().await?;
+        let result = rows
+            .into_iter()
+            .map(|row| {
+                let stats = MatchupStats {
+                    matches: row.matches,
+                    team1_wins: row.team1_wins,
+                    team2_wins: row.team2_wins,
+                    win_rate: row.win_rate,
+                };
+                (row.period as usize, stats)
+            })
+            .collect();
+        Ok(result)
+    }
 
-        let (cond1, cond2) = match (team1.is_empty(), team2.is_empty()) {
-            (true, true) => return Ok(vec![]),
-            (true, false) => (side_check("radiant", team2), side_check("dire", team2)),
-            (false, true) => (side_check("radiant", team1), side_check("dire", team1)),
-            (false, false) => (
-                format!(
-                    "({} AND {})",
-                    side_check("radiant", team1),
-                    side_check("dire", team2)
-                ),
-                format!(
-                    "({} AND {})",
-                    side_check("radiant", team2),
-                    side_check("dire", team1)
-                ),
-            ),
+    async fn candidate_stats(&self, filter: &DraftFilter) -> anyhow::Result<Vec<HeroStats>> {
+        let Some((cond1, cond2)) = Self::team_conditions(filter) else {
+            return Ok(vec![]);
         };
 
+        // same as query_stats, team1 is considered radiant if both conditions hold
         let query = format!(
-            "SELECT ?fields FROM {}.{} WHERE ({} OR {}) ORDER BY match_id DESC LIMIT {} OFFSET {}",
-            self.database, self.table, cond1, cond2, limit, offset
+            "SELECT
+                arrayJoin(array(untuple(if({0}, radiant, dire)))) AS hero,
+                count() AS games,
+                countIf(({0} AND radiant_win) OR (NOT {0} AND NOT radiant_win)) AS wins
+            FROM {2}.{3} WHERE ({0} OR {1}) AND {WITH_OUTCOME}{4}
+            GROUP BY hero HAVING hero NOT IN ({5})
+            ORDER BY hero",
+            cond1,
+            cond2,
+            self.database,
+            self.table,
+            Self::match_conditions(filter),
+            // 0 is never a hero, it keeps the list valid without allies
+            filter.team1.iter().chain(&[0]).format(",")
         );
-        self.client.query(&query).fetch_all().await
+        Ok(self.client.query(&query).fetch_all().await?)
     }
 
-    pub async fn save_match_drafts(&self, drafts: &[MatchDraft]) -> Result<(), Error> {
+    async fn hero_synergy(&self, hero: u8) -> anyhow::Result<Vec<HeroPairStats>> {
+        self.pair_stats("hero_synergy", hero).await
+    }
+
+    async fn hero_counters(&self, hero: u8) -> anyhow::Result<Vec<HeroPairStats>> {
+        self.pair_stats("hero_counters", hero).await
+    }
+
+    async fn backfill_pairs(&self) -> anyhow::Result<()> {
+        Self::locked(&self.client, self.backfill()).await
+    }
+
+    async fn pairs_backfilled(&self) -> anyhow::Result<bool> {
+        Ok(Self::pairs_pending(&self.client).await?.is_empty())
+    }
+
+    async fn save_match_drafts(&self, drafts: &[MatchDraft]) -> anyhow::Result<()> {
         let mut insert = self.client.insert(&self.table)?;
         for draft in drafts {
             insert.write(draft).await?;
@@ -118,10 +699,124 @@ impl Database {
         Ok(())
     }
 
-    pub async fn save_progress(&self, progress: Progress) -> Result<(), Error> {
+    async fn save_match_players(&self, players: &[MatchPlayer]) -> anyhow::Result<()> {
+        let mut insert = self.client.insert("match_players")?;
+        for player in players {
+            insert.write(player).await?;
+        }
+        insert.end().await?;
+        Ok(())
+    }
+
+    async fn query_player(
+        &self,
+        account_id: u32,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<PlayerMatch>> {
+        let query = format!(
+            "SELECT ?fields FROM {}.match_players WHERE account_id = {}
+            ORDER BY match_id DESC LIMIT 1 BY match_id LIMIT {} OFFSET {}",
+            self.database, account_id, limit, offset
+        );
+        let players: Vec<MatchPlayer> = self.client.query(&query).fetch_all().await?;
+        if players.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let query = format!(
+            "SELECT ?fields FROM {}.{} WHERE match_id IN ({}) LIMIT 1 BY match_id",
+            self.database,
+            self.table,
+            players.iter().map(|player| player.match_id).format(",")
+        );
+        let drafts: Vec<MatchDraft> = self.client.query(&query).fetch_all().await?;
+        let drafts: HashMap<_, _> = drafts
+            .into_iter()
+            .map(|draft| (draft.match_id, draft))
+            .collect();
+        let result = players
+            .into_iter()
+            .filter_map(|player| {
+                let draft = drafts.get(&player.match_id)?.clone();
+                Some(PlayerMatch { player, draft })
+            })
+            .collect();
+        Ok(result)
+    }
+
+    async fn save_progress(&self, progress: Progress) -> anyhow::Result<()> {
         let mut insert = self.client.insert("progress")?;
         insert.write(&progress).await?;
         insert.end().await?;
         Ok(())
     }
+
+    async fn save_rejected(&self, rejected: &[RejectedMatch]) -> anyhow::Result<()> {
+        let mut insert = self.client.insert("rejected_matches")?;
+        for row in rejected {
+            insert.write(row).await?;
+        }
+        insert.end().await?;
+        Ok(())
+    }
+
+    async fn rejected_counts(&self) -> anyhow::Result<Vec<RejectedCount>> {
+        let query = format!(
+            "SELECT reason, count() AS count FROM {}.rejected_matches GROUP BY reason ORDER BY reason",
+            self.database
+        );
+        Ok(self.client.query(&query).fetch_all().await?)
+    }
+
+    async fn load_collected(&self) -> anyhow::Result<Option<Vec<(u64, u64)>>> {
+        let query = "SELECT ?fields FROM collector_state ORDER BY timestamp DESC LIMIT 1";
+        let state: Option<CollectedRanges> = self.client.query(query).fetch_optional().await?;
+        Ok(state.map(|state| state.collected))
+    }
+
+    async fn save_collected(&self, collected: &[(u64, u64)]) -> anyhow::Result<()> {
+        let Some(state) = CollectedRanges::new(collected.to_vec()) else {
+            anyhow::bail!("system time is before unix epoch");
+        };
+        let mut insert = self.client.insert("collector_state")?;
+        insert.write(&state).await?;
+        insert.end().await?;
+        Ok(())
+    }
+
+    async fn scan_drafts(&self, after: u64, limit: usize) -> anyhow::Result<Vec<MatchDraft>> {
+        let query = format!(
+            "SELECT ?fields FROM {}.{} WHERE match_id > {} ORDER BY match_id LIMIT {}",
+            self.database, self.table, after, limit
+        );
+        Ok(self.client.query(&query).fetch_all().await?)
+    }
+
+    async fn match_id_quantile(&self, after: u64, level: f64) -> anyhow::Result<Option<u64>> {
+        // sampled rather than exact, sorting every match id would not fit in memory
+        let query = format!(
+            "SELECT toUInt64(quantile({})(match_id)) FROM {}.{}
+            WHERE match_id > {} AND {WITH_OUTCOME}
+            HAVING count() > 0",
+            level.clamp(0.0, 1.0),
+            self.database,
+            self.table,
+            after
+        );
+        Ok(self.client.query(&query).fetch_optional().await?)
+    }
+
+    async fn summary(&self) -> anyhow::Result<StoreSummary> {
+        let query = format!(
+            "SELECT
+                count() AS drafts,
+                uniqExact(match_id) AS matches,
+                min(match_id) AS min_match_id,
+                max(match_id) AS max_match_id
+            FROM {}.{}",
+            self.database, self.table
+        );
+        Ok(self.client.query(&query).fetch_one().await?)
+    }
 }
@@ -1,7 +1,8 @@
 use std::time::SystemTime;
 
 use clickhouse::Row;
-use kez::dota2::{Match, Side};
+use itertools::Itertools;
+use kez::dota2::{Account, Match, Side};
 use serde::{Deserialize, Serialize};
 
 #[derive(Row, Debug, Clone, Serialize, Deserialize)]
@@ -9,6 +10,101 @@ pub struct MatchDraft {
     pub match_id: u64,
     pub radiant: [u8; 5],
     pub dire: [u8; 5],
+    // only meaningful if has_outcome, drafts stored before outcomes read as dire wins
+    pub radiant_win: bool,
+    // seconds since unix epoch, 0 for drafts stored before outcomes
+    pub start_time: u64,
+    // seconds
+    pub duration: u32,
+    pub game_mode: u8,
+    pub lobby_type: u8,
+    pub cluster: u32,
+    // facet of the hero at the same position, 0 for drafts stored before facets
+    #[serde(default)]
+    pub radiant_facets: [u8; 5],
+    #[serde(default)]
+    pub dire_facets: [u8; 5],
+}
+
+impl MatchDraft {
+    // drafts stored before outcomes were added have every new column defaulted,
+    // a real match never starts at the unix epoch
+    pub fn has_outcome(&self) -> bool {
+        self.start_time != 0
+    }
+}
+
+// a draft with outcome, later match ids start later
+#[cfg(test)]
+pub fn test_draft(match_id: u64, radiant: [u8; 5], dire: [u8; 5], radiant_win: bool) -> MatchDraft {
+    MatchDraft {
+        match_id,
+        radiant,
+        dire,
+        radiant_win,
+        start_time: 1_700_000_000 + match_id,
+        duration: 2400,
+        game_mode: 22,
+        lobby_type: 7,
+        cluster: 0,
+        radiant_facets: [1; 5],
+        dire_facets: [1; 5],
+    }
+}
+
+#[derive(Row, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MatchupStats {
+    pub matches: u64,
+    pub team1_wins: u64,
+    pub team2_wins: u64,
+    // team1 win rate
+    pub win_rate: f64,
+}
+
+impl MatchupStats {
+    pub fn add(&mut self, team1_win: bool) {
+        self.matches += 1;
+        if team1_win {
+            self.team1_wins += 1;
+        } else {
+            self.team2_wins += 1;
+        }
+        self.win_rate = self.team1_wins as f64 / self.matches as f64;
+    }
+}
+
+// results of a hero in matching drafts
+#[derive(Row, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HeroStats {
+    pub hero: u8,
+    pub games: u64,
+    pub wins: u64,
+}
+
+// how a hero fares with or against another hero
+#[derive(Row, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HeroPairStats {
+    pub other: u8,
+    pub games: u64,
+    // won by the first hero
+    pub wins: u64,
+    pub win_rate: f64,
+}
+
+#[derive(Row, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreSummary {
+    // rows in drafts, a match may have been stored more than once
+    pub drafts: u64,
+    // distinct match ids
+    pub matches: u64,
+    pub min_match_id: u64,
+    pub max_match_id: u64,
+}
+
+#[derive(Row, Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedCount {
+    pub reason: String,
+    pub count: u64,
 }
 
 #[derive(Row, Debug, Clone, Serialize, Deserialize)]
@@ -30,40 +126,299 @@ impl Progress {
     }
 }
 
-impl From<&Match> for MatchDraft {
-    fn from(value: &Match) -> Self {
+// every save appends a snapshot of all collected ranges, the latest one wins
+#[derive(Row, Debug, Clone, Serialize, Deserialize)]
+pub struct CollectedRanges {
+    // milliseconds, several snapshots may be saved within one second
+    pub timestamp: u64,
+    pub collected: Vec<(u64, u64)>,
+}
+
+impl CollectedRanges {
+    pub fn new(collected: Vec<(u64, u64)>) -> Option<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?
+            .as_millis() as u64;
+        Some(Self {
+            timestamp,
+            collected,
+        })
+    }
+}
+
+// details of a player in a stored draft, the api does not report lanes
+#[derive(Row, Debug, Clone, Serialize, Deserialize)]
+pub struct MatchPlayer {
+    pub match_id: u64,
+    pub hero: u8,
+    pub facet: u8,
+    // 0-4 for radiant, 128-132 for dire
+    pub player_slot: u8,
+    // None for anonymous players and bots
+    pub account_id: Option<u32>,
+    // 0 if the player stayed
+    pub leaver_status: u8,
+}
+
+impl MatchPlayer {
+    pub fn is_radiant(&self) -> bool {
+        self.player_slot & 0x80 == 0
+    }
+
+    pub fn from_match(value: &Match) -> Vec<Self> {
         let match_id = u64::from(value.match_id);
-        let mut radiant = [0; 5];
-        let mut dire = [0; 5];
-        let mut ridx = 0;
-        let mut didx = 0;
-        value.players.iter().for_each(|player| {
+        value
+            .players
+            .iter()
+            .map(|player| {
+                let (side, position) = player.slot;
+                let (hero, facet) = player.hero.into();
+                let player_slot = match side {
+                    Side::Radiant => position,
+                    Side::Dire => 0x80 | position,
+                };
+                let account_id = match player.account {
+                    Account::User(id) => Some(id),
+                    _ => None,
+                };
+                Self {
+                    match_id,
+                    hero,
+                    facet,
+                    player_slot,
+                    account_id,
+                    leaver_status: player.leave_status.map_or(0, u8::from),
+                }
+            })
+            .collect()
+    }
+}
+
+// a draft seen from one of its players
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerMatch {
+    pub player: MatchPlayer,
+    pub draft: MatchDraft,
+}
+
+impl PlayerMatch {
+    pub fn won(&self) -> bool {
+        self.player.is_radiant() == self.draft.radiant_win
+    }
+}
+
+// why a match could not be turned into a draft
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    // a side without exactly five players
+    PlayerCount,
+    // a player without a hero, 0 as hero id
+    MissingHero,
+    // a hero picked more than once
+    DuplicateHero,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::PlayerCount => "player_count",
+            RejectReason::MissingHero => "missing_hero",
+            RejectReason::DuplicateHero => "duplicate_hero",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// a match kept out of drafts, stored so dropped data can be accounted for
+#[derive(Row, Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedMatch {
+    pub match_id: u64,
+    pub match_seq_num: u64,
+    pub reason: String,
+    // when the match was rejected, in seconds
+    pub timestamp: u64,
+}
+
+impl RejectedMatch {
+    pub fn new(value: &Match, reason: RejectReason) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|dur| dur.as_secs())
+            .unwrap_or_default();
+        Self {
+            match_id: u64::from(value.match_id),
+            match_seq_num: u64::from(value.match_seq_num),
+            reason: reason.to_string(),
+            timestamp,
+        }
+    }
+}
+
+impl TryFrom<&Match> for MatchDraft {
+    type Error = RejectReason;
+
+    fn try_from(value: &Match) -> Result<Self, Self::Error> {
+        let mut radiant = Vec::with_capacity(5);
+        let mut dire = Vec::with_capacity(5);
+        for player in &value.players {
             let (side, _) = player.slot;
-            let (hero_id, _facet) = player.hero.into();
-            // ideally there should be exactly 5 randiant and 5 dire
-            // however we live in a bizarre world
+            let (hero_id, facet): (u8, u8) = player.hero.into();
+            if hero_id == 0 {
+                return Err(RejectReason::MissingHero);
+            }
             match side {
-                Side::Radiant if ridx < 5 => {
-                    radiant[ridx] = hero_id;
-                    ridx += 1;
-                }
-                Side::Dire if didx < 5 => {
-                    dire[didx] = hero_id;
-                    didx += 1;
-                }
-                _ => log::warn!("problematic match {}", match_id),
+                Side::Radiant => radiant.push((hero_id, facet)),
+                Side::Dire => dire.push((hero_id, facet)),
             }
-        });
-        Self {
-            match_id,
+        }
+        if radiant.len() != 5 || dire.len() != 5 {
+            return Err(RejectReason::PlayerCount);
+        }
+        let heroes = |side: &[(u8, u8)]| std::array::from_fn(|idx| side[idx].0);
+        let facets = |side: &[(u8, u8)]| std::array::from_fn(|idx| side[idx].1);
+        let (radiant, radiant_facets, dire, dire_facets) = (
+            heroes(&radiant),
+            facets(&radiant),
+            heroes(&dire),
+            facets(&dire),
+        );
+        if !radiant.iter().chain(dire.iter()).all_unique() {
+            return Err(RejectReason::DuplicateHero);
+        }
+
+        let radiant_win = value.winner == Side::Radiant;
+        let start_time = value
+            .start_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|dur| dur.as_secs())
+            .unwrap_or_default();
+        let duration = value.duration.as_secs() as u32;
+        Ok(Self {
+            match_id: u64::from(value.match_id),
             radiant,
             dire,
-        }
+            radiant_win,
+            start_time,
+            duration,
+            game_mode: value.mode.into(),
+            lobby_type: value.lobby_type.into(),
+            cluster: value.cluster,
+            radiant_facets,
+            dire_facets,
+        })
+    }
+}
+
+// a finished match, players are (hero, facet) in slot order
+#[cfg(test)]
+pub fn test_match(radiant: &[(u8, u8)], dire: &[(u8, u8)]) -> Match {
+    use kez::dota2::get_match_history_by_seq_num as raw;
+
+    let player = |slot: u8, &(hero_id, hero_variant): &(u8, u8)| raw::Player {
+        player_slot: slot,
+        hero_id,
+        hero_variant,
+        ..Default::default()
+    };
+    let radiant = radiant
+        .iter()
+        .zip(0..)
+        .map(|(hero, slot)| player(slot, hero));
+    let dire = dire
+        .iter()
+        .zip(128..)
+        .map(|(hero, slot)| player(slot, hero));
+    raw::Match {
+        players: radiant.chain(dire).collect(),
+        radiant_win: true,
+        duration: 2400,
+        start_time: 1716336000,
+        match_id: 7750000000,
+        match_seq_num: 6500000000,
+        cluster: 123,
+        lobby_type: 7,
+        game_mode: 22,
+        ..Default::default()
     }
+    .into()
 }
 
-impl From<Match> for MatchDraft {
-    fn from(value: Match) -> Self {
-        Self::from(&value)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIANT: [(u8, u8); 5] = [(1, 1), (2, 2), (3, 1), (4, 3), (5, 2)];
+    const DIRE: [(u8, u8); 5] = [(6, 1), (7, 2), (8, 1), (9, 2), (10, 1)];
+
+    #[test]
+    fn full_match_becomes_a_draft() {
+        let draft = MatchDraft::try_from(&test_match(&RADIANT, &DIRE)).unwrap();
+        assert_eq!(draft.match_id, 7750000000);
+        assert_eq!(draft.radiant, [1, 2, 3, 4, 5]);
+        assert_eq!(draft.radiant_facets, [1, 2, 1, 3, 2]);
+        assert_eq!(draft.dire, [6, 7, 8, 9, 10]);
+        assert_eq!(draft.dire_facets, [1, 2, 1, 2, 1]);
+        assert!(draft.radiant_win);
+        assert!(draft.has_outcome());
+        assert_eq!(draft.start_time, 1716336000);
+        assert_eq!(draft.duration, 2400);
+        assert_eq!((draft.game_mode, draft.lobby_type), (22, 7));
+        assert_eq!(draft.cluster, 123);
+    }
+
+    #[test]
+    fn sides_without_five_players_are_rejected() {
+        let reject = |radiant: &[(u8, u8)], dire: &[(u8, u8)]| {
+            MatchDraft::try_from(&test_match(radiant, dire)).unwrap_err()
+        };
+        assert_eq!(reject(&RADIANT[..4], &DIRE), RejectReason::PlayerCount);
+        assert_eq!(reject(&RADIANT, &DIRE[..4]), RejectReason::PlayerCount);
+        let six = [RADIANT.as_slice(), &[(11, 1)]].concat();
+        assert_eq!(reject(&six, &DIRE), RejectReason::PlayerCount);
+        assert_eq!(reject(&[], &[]), RejectReason::PlayerCount);
+    }
+
+    #[test]
+    fn players_without_hero_are_rejected() {
+        let mut dire = DIRE;
+        dire[2] = (0, 0);
+        let mat = test_match(&RADIANT, &dire);
+        assert_eq!(
+            MatchDraft::try_from(&mat).unwrap_err(),
+            RejectReason::MissingHero
+        );
+
+        // a missing hero is reported even if a side is short as well
+        let mat = test_match(&RADIANT[..4], &dire);
+        assert_eq!(
+            MatchDraft::try_from(&mat).unwrap_err(),
+            RejectReason::MissingHero
+        );
+    }
+
+    #[test]
+    fn heroes_picked_twice_are_rejected() {
+        let mut dire = DIRE;
+        dire[4] = (1, 2);
+        let mat = test_match(&RADIANT, &dire);
+        assert_eq!(
+            MatchDraft::try_from(&mat).unwrap_err(),
+            RejectReason::DuplicateHero
+        );
+
+        let mut radiant = RADIANT;
+        radiant[1] = radiant[0];
+        let mat = test_match(&radiant, &DIRE);
+        assert_eq!(
+            MatchDraft::try_from(&mat).unwrap_err(),
+            RejectReason::DuplicateHero
+        );
     }
 }
@@ -0,0 +1,238 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{dota2::MatchDraft, heroes::HeroFacet};
+
+// side team1 plays on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    #[default]
+    Any,
+    Radiant,
+    Dire,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Any => "any",
+            Side::Radiant => "radiant",
+            Side::Dire => "dire",
+        }
+    }
+}
+
+// unix timestamp in seconds, or a YYYY-MM-DD date meaning its midnight in utc
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TimeRef {
+    Timestamp(u64),
+    Date(String),
+}
+
+impl TimeRef {
+    pub fn resolve(&self) -> anyhow::Result<u64> {
+        match self {
+            TimeRef::Timestamp(timestamp) => Ok(*timestamp),
+            TimeRef::Date(date) => match date.trim().parse() {
+                Ok(timestamp) => Ok(timestamp),
+                Err(_) => parse_date(date),
+            },
+        }
+    }
+}
+
+fn is_leap(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+// days since 1970-01-01 of a proleptic gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// YYYY-MM-DD to the unix timestamp of its midnight in utc
+pub fn parse_date(date: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid date {}, expected YYYY-MM-DD", date);
+    let parts: Vec<i64> = date
+        .trim()
+        .split('-')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<anyhow::Result<_>>()?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let days_in_month = match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid()),
+    };
+    if year < 1970 || !(1..=days_in_month).contains(&day) {
+        return Err(invalid());
+    }
+    Ok(days_from_civil(year, month, day) as u64 * 86400)
+}
+
+// conditions a draft has to satisfy, team1 may play on either side
+#[derive(Debug, Clone, Default)]
+pub struct DraftFilter {
+    // heroes team1/team2 must all have
+    pub team1: Vec<u8>,
+    pub team2: Vec<u8>,
+    // (hero, facet) pairs team1/team2 must have, the heroes are in team1/team2 as well
+    pub team1_facets: Vec<HeroFacet>,
+    pub team2_facets: Vec<HeroFacet>,
+    // heroes team1/team2 must not have
+    pub exclude_team1: Vec<u8>,
+    pub exclude_team2: Vec<u8>,
+    // heroes not picked by anyone
+    pub exclude_any: Vec<u8>,
+    pub side: Side,
+    // start time in [since, until)
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    // match id in [min_match_id, max_match_id]
+    pub min_match_id: Option<u64>,
+    pub max_match_id: Option<u64>,
+    // allowed game modes and lobby types, empty for any
+    pub game_modes: Vec<u8>,
+    pub lobby_types: Vec<u8>,
+}
+
+fn has_all(side: &[u8; 5], heroes: &[u8]) -> bool {
+    heroes.iter().all(|hero| side.contains(hero))
+}
+
+fn has_any(side: &[u8; 5], heroes: &[u8]) -> bool {
+    heroes.iter().any(|hero| side.contains(hero))
+}
+
+fn has_facets(side: &[u8; 5], facets: &[u8; 5], picks: &[HeroFacet]) -> bool {
+    picks.iter().all(|&pick| {
+        side.iter()
+            .copied()
+            .zip(facets.iter().copied())
+            .any(|hero| hero == pick)
+    })
+}
+
+impl DraftFilter {
+    // a filter without any hero would match every single draft
+    pub fn is_empty(&self) -> bool {
+        self.team1.is_empty()
+            && self.team2.is_empty()
+            && self.exclude_team1.is_empty()
+            && self.exclude_team2.is_empty()
+            && self.exclude_any.is_empty()
+    }
+
+    // each team as (heroes, facets)
+    fn check(&self, team1: (&[u8; 5], &[u8; 5]), team2: (&[u8; 5], &[u8; 5])) -> bool {
+        let ((team1, facets1), (team2, facets2)) = (team1, team2);
+        has_all(team1, &self.team1)
+            && has_all(team2, &self.team2)
+            && has_facets(team1, facets1, &self.team1_facets)
+            && has_facets(team2, facets2, &self.team2_facets)
+            && !has_any(team1, &self.exclude_team1)
+            && !has_any(team2, &self.exclude_team2)
+            && !has_any(team1, &self.exclude_any)
+            && !has_any(team2, &self.exclude_any)
+    }
+
+    // narrow the start time range down to [since, until)
+    pub fn within(&mut self, since: u64, until: Option<u64>) {
+        self.since = Some(self.since.map_or(since, |old| old.max(since)));
+        self.until = match (self.until, until) {
+            (Some(old), Some(until)) => Some(old.min(until)),
+            (old, until) => old.or(until),
+        };
+    }
+
+    // conditions not depending on the side of team1
+    fn check_match(&self, draft: &MatchDraft) -> bool {
+        (self.game_modes.is_empty() || self.game_modes.contains(&draft.game_mode))
+            && (self.lobby_types.is_empty() || self.lobby_types.contains(&draft.lobby_type))
+            && self.since.is_none_or(|since| draft.start_time >= since)
+            && self.until.is_none_or(|until| draft.start_time < until)
+            && self.min_match_id.is_none_or(|min| draft.match_id >= min)
+            && self.max_match_id.is_none_or(|max| draft.match_id <= max)
+    }
+
+    // whether the draft matches with team1 on (radiant, dire)
+    pub fn sides(&self, draft: &MatchDraft) -> (bool, bool) {
+        if !self.check_match(draft) {
+            return (false, false);
+        }
+        let radiant_side = (&draft.radiant, &draft.radiant_facets);
+        let dire_side = (&draft.dire, &draft.dire_facets);
+        let radiant = self.side != Side::Dire && self.check(radiant_side, dire_side);
+        let dire = self.side != Side::Radiant && self.check(dire_side, radiant_side);
+        (radiant, dire)
+    }
+
+    // side team1 is on in a matching draft, radiant if both sides match
+    pub fn team1_side(&self, draft: &MatchDraft) -> Side {
+        match self.sides(draft) {
+            (false, true) => Side::Dire,
+            _ => Side::Radiant,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_from_civil_counts_from_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1970, 3, 1), 59);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn parse_date_handles_leap_years() {
+        assert_eq!(parse_date("2000-02-29").unwrap(), 951782400);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 1709164800);
+        assert_eq!(parse_date("2024-03-01").unwrap(), 1709251200);
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("2100-02-29").is_err());
+    }
+
+    #[test]
+    fn parse_date_checks_bounds() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date(" 2024-05-22 ").unwrap(), 1716336000);
+        assert_eq!(parse_date("2024-12-31").unwrap(), 1735603200);
+        for date in [
+            "1969-12-31",
+            "2024-00-10",
+            "2024-13-01",
+            "2024-04-31",
+            "2024-01-00",
+            "2024-01",
+            "2024-01-01-01",
+            "yesterday",
+        ] {
+            assert!(parse_date(date).is_err(), "{}", date);
+        }
+    }
+
+    #[test]
+    fn time_ref_accepts_timestamps_and_dates() {
+        let resolve = |value: &str| serde_json::from_str::<TimeRef>(value)?.resolve();
+        assert_eq!(resolve("1716336000").unwrap(), 1716336000);
+        assert_eq!(resolve("\"1716336000\"").unwrap(), 1716336000);
+        assert_eq!(resolve("\"2024-05-22\"").unwrap(), 1716336000);
+        assert!(resolve("\"2024-02-30\"").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hero {
+    pub id: u8,
+    // internal name without the npc_dota_hero_ prefix
+    pub name: String,
+    pub localized_name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+// hero id or any known name of a hero
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HeroRef {
+    Id(u8),
+    Name(String),
+}
+
+// (hero id, facet)
+pub type HeroFacet = (u8, u8);
+
+// a hero, optionally constrained to one of its facets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HeroPick {
+    Hero(HeroRef),
+    Faceted { hero: HeroRef, facet: u8 },
+}
+
+pub struct HeroRegistry {
+    heroes: Vec<Hero>,
+    // normalized names and aliases to hero id
+    lookup: HashMap<String, u8>,
+}
+
+// lowercase alphanumeric only, so "Anti-Mage" and "antimage" are the same hero
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|ch| ch.to_ascii_lowercase())
+        .collect()
+}
+
+impl HeroRegistry {
+    pub fn new(heroes: Vec<Hero>) -> anyhow::Result<Self> {
+        let mut lookup = HashMap::new();
+        for hero in &heroes {
+            let names = [&hero.name, &hero.localized_name]
+                .into_iter()
+                .chain(hero.aliases.iter());
+            for name in names {
+                match lookup.insert(normalize(name), hero.id) {
+                    Some(id) if id != hero.id => {
+                        anyhow::bail!("hero name {} is used by both {} and {}", name, id, hero.id)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self { heroes, lookup })
+    }
+
+    // heroes shipped with this binary
+    pub fn embedded() -> Self {
+        let heroes = serde_json::from_str(include_str!("heroes.json")).unwrap();
+        Self::new(heroes).unwrap()
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::new(serde_json::from_str(&content)?)
+    }
+
+    pub fn heroes(&self) -> &[Hero] {
+        &self.heroes
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Hero> {
+        self.heroes.iter().find(|hero| hero.id == id)
+    }
+
+    // localized name, or the id itself for heroes unknown to us
+    pub fn name(&self, id: u8) -> String {
+        self.get(id)
+            .map_or_else(|| id.to_string(), |hero| hero.localized_name.clone())
+    }
+
+    // ids are accepted as is, so heroes newer than the registry can still be queried
+    pub fn resolve(&self, hero: &HeroRef) -> anyhow::Result<u8> {
+        match hero {
+            HeroRef::Id(id) => Ok(*id),
+            HeroRef::Name(name) => match name.trim().parse() {
+                Ok(id) => Ok(id),
+                Err(_) => self
+                    .lookup
+                    .get(&normalize(name))
+                    .copied()
+                    .ok_or_else(|| anyhow::anyhow!("unknown hero {}", name)),
+            },
+        }
+    }
+
+    pub fn resolve_all(&self, heroes: &[HeroRef]) -> anyhow::Result<Vec<u8>> {
+        heroes.iter().map(|hero| self.resolve(hero)).collect()
+    }
+
+    // all picked heroes, and (hero, facet) pairs of picks constrained to a facet
+    pub fn resolve_picks(&self, picks: &[HeroPick]) -> anyhow::Result<(Vec<u8>, Vec<HeroFacet>)> {
+        let mut heroes = Vec::with_capacity(picks.len());
+        let mut facets = vec![];
+        for pick in picks {
+            match pick {
+                HeroPick::Hero(hero) => heroes.push(self.resolve(hero)?),
+                HeroPick::Faceted { hero, facet } => {
+                    let hero = self.resolve(hero)?;
+                    heroes.push(hero);
+                    facets.push((hero, *facet));
+                }
+            }
+        }
+        Ok((heroes, facets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hero(id: u8, name: &str, localized_name: &str, aliases: &[&str]) -> Hero {
+        Hero {
+            id,
+            name: name.to_string(),
+            localized_name: localized_name.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+        }
+    }
+
+    fn resolve(heroes: &HeroRegistry, name: &str) -> anyhow::Result<u8> {
+        heroes.resolve(&HeroRef::Name(name.to_string()))
+    }
+
+    #[test]
+    fn normalize_keeps_lowercase_alphanumerics() {
+        assert_eq!(normalize("Anti-Mage"), "antimage");
+        assert_eq!(normalize(" Nature's Prophet "), "naturesprophet");
+        assert_eq!(normalize("WR"), "wr");
+    }
+
+    #[test]
+    fn embedded_registry_resolves_names_aliases_and_ids() {
+        let heroes = HeroRegistry::embedded();
+        assert_eq!(resolve(&heroes, "AM").unwrap(), 1);
+        assert_eq!(resolve(&heroes, "Anti-Mage").unwrap(), 1);
+        assert_eq!(resolve(&heroes, "antimage").unwrap(), 1);
+        assert_eq!(resolve(&heroes, "wr").unwrap(), 21);
+        assert_eq!(resolve(&heroes, "Windranger").unwrap(), 21);
+        assert_eq!(resolve(&heroes, "1").unwrap(), 1);
+        assert_eq!(resolve(&heroes, " 250 ").unwrap(), 250);
+        assert_eq!(heroes.resolve(&HeroRef::Id(21)).unwrap(), 21);
+        assert!(resolve(&heroes, "nobody").is_err());
+        assert_eq!(heroes.name(1), "Anti-Mage");
+        assert_eq!(heroes.name(250), "250");
+    }
+
+    #[test]
+    fn colliding_names_are_rejected() {
+        let heroes = vec![
+            hero(1, "antimage", "Anti-Mage", &["am"]),
+            hero(2, "axe", "Axe", &["AM"]),
+        ];
+        assert!(HeroRegistry::new(heroes).is_err());
+
+        let heroes = vec![
+            hero(1, "antimage", "Anti-Mage", &[]),
+            hero(2, "anti_mage", "Axe", &[]),
+        ];
+        assert!(HeroRegistry::new(heroes).is_err());
+
+        // a hero may repeat its own names
+        let heroes = vec![hero(1, "antimage", "Anti-Mage", &["anti mage"])];
+        assert!(HeroRegistry::new(heroes).is_ok());
+    }
+}
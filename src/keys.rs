@@ -0,0 +1,94 @@
+use std::{sync::Mutex, time::Duration};
+
+use kez::Client;
+use tokio::time::Instant;
+
+use crate::rate::RateController;
+
+struct ApiKey {
+    client: Client,
+    // time of the latest request issued or reserved by this key
+    last: Instant,
+    rate: RateController,
+}
+
+// a pool of api keys, each key has its own request rate and backoff state
+pub struct KeyPool {
+    keys: Mutex<Vec<ApiKey>>,
+}
+
+impl KeyPool {
+    pub fn new(keys: &[String], rate: RateController) -> anyhow::Result<Self> {
+        if keys.is_empty() {
+            anyhow::bail!("at least one steam api key is required");
+        }
+        let now = Instant::now();
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let client = reqwest::Client::builder()
+                    .timeout(Duration::from_secs(60))
+                    .connect_timeout(Duration::from_secs(30))
+                    .build()?;
+                let client = Client::with_client(client, key.as_str());
+                Ok(ApiKey {
+                    client,
+                    last: now,
+                    rate: rate.clone(),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        log::info!("Using {} api key(s)", keys.len());
+        let keys = Mutex::new(keys);
+        Ok(Self { keys })
+    }
+
+    // any client for requests which are not rate controlled
+    pub fn client(&self) -> Client {
+        self.get(0)
+    }
+
+    // wait until some key is allowed to issue a request, returns its index
+    pub async fn acquire(&self) -> usize {
+        let (idx, at) = {
+            let mut keys = self.keys.lock().unwrap();
+            let (idx, key) = keys
+                .iter_mut()
+                .enumerate()
+                .min_by_key(|(_, key)| key.rate.next(key.last))
+                .unwrap();
+            // reserve the slot before sleeping so concurrent callers pick another one
+            let at = std::cmp::max(key.rate.next(key.last), Instant::now());
+            key.last = at;
+            (idx, at)
+        };
+        tokio::time::sleep_until(at).await;
+        idx
+    }
+
+    pub fn get(&self, idx: usize) -> Client {
+        self.keys.lock().unwrap()[idx].client.clone()
+    }
+
+    // total requests per second of all keys
+    pub fn rate(&self) -> f64 {
+        let keys = self.keys.lock().unwrap();
+        keys.iter().map(|key| key.rate.rate()).sum()
+    }
+
+    pub fn succeed(&self, idx: usize) {
+        self.keys.lock().unwrap()[idx].rate.succeed();
+    }
+
+    // slow down a key and hold it for a while
+    pub fn fail(&self, idx: usize, retry_after: Option<Duration>) {
+        let mut keys = self.keys.lock().unwrap();
+        let key = &mut keys[idx];
+        key.rate.fail(Instant::now(), retry_after);
+        log::warn!(
+            "Key #{} backing off, interval is now {}ms",
+            idx,
+            key.rate.interval().as_millis()
+        );
+    }
+}
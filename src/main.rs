@@ -1,41 +1,202 @@
 mod args;
 mod collector;
+mod commands;
 mod database;
 mod dota2;
+mod filter;
+mod heroes;
+mod keys;
+mod memory;
+mod model;
+mod patches;
+mod rate;
 mod scheduler;
 mod service;
+mod store;
 
 use std::{sync::Arc, time::Duration};
 
-use axum::{routing::post, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 use clap::Parser;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
 
-use args::Args;
+use args::{Args, CollectArgs, Command, ServeArgs, Storage};
 use database::Database;
+use heroes::HeroRegistry;
+use memory::MemoryStore;
+use model::Model;
+use patches::PatchList;
+use rate::RateController;
 use scheduler::Scheduler;
-use service::{find_matches, AppState};
-
-async fn serve(database: Arc<Database>, address: String) -> anyhow::Result<()> {
-    let state = AppState::new(database);
-    let app = Router::new().route(
-        "/",
-        post({
-            let state = Arc::new(state);
-            move |body| find_matches(body, state)
-        }),
-    );
+use service::{
+    find_matches, hero_counters, hero_synergy, list_heroes, list_patches, matchup_stats,
+    patch_stats, player_matches, predict, recommend, AppState,
+};
+use store::DraftStore;
+
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {},
+    }
+    Ok(())
+}
+
+async fn serve(
+    state: AppState,
+    args: ServeArgs,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let state = match &args.model {
+        Some(path) => state.with_model(Model::load(path)?),
+        None => state,
+    };
+    let state = Arc::new(state);
+
+    // reload hero registry and patches on SIGHUP
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            while hangup.recv().await.is_some() {
+                if let Err(err) = state.reload_heroes() {
+                    log::error!("Failed to reload heroes: {}", err);
+                }
+                if let Err(err) = state.reload_patches() {
+                    log::error!("Failed to reload patches: {}", err);
+                }
+            }
+        }
+    });
+
+    let app = Router::new()
+        .route(
+            "/",
+            post({
+                let state = state.clone();
+                move |body| find_matches(body, state)
+            }),
+        )
+        .route(
+            "/stats",
+            post({
+                let state = state.clone();
+                move |body| matchup_stats(body, state)
+            }),
+        )
+        .route(
+            "/stats/patches",
+            post({
+                let state = state.clone();
+                move |body| patch_stats(body, state)
+            }),
+        )
+        .route(
+            "/recommend",
+            post({
+                let state = state.clone();
+                move |body| recommend(body, state)
+            }),
+        )
+        .route(
+            "/predict",
+            post({
+                let state = state.clone();
+                move |body| predict(body, state)
+            }),
+        )
+        .route(
+            "/patches",
+            get({
+                let state = state.clone();
+                move || list_patches(state)
+            }),
+        )
+        .route(
+            "/players/{account_id}",
+            get({
+                let state = state.clone();
+                move |account_id, page| player_matches(account_id, page, state)
+            }),
+        )
+        .route(
+            "/heroes",
+            get({
+                let state = state.clone();
+                move || list_heroes(state)
+            }),
+        )
+        .route(
+            "/heroes/{hero}/synergy",
+            get({
+                let state = state.clone();
+                move |hero| hero_synergy(hero, state)
+            }),
+        )
+        .route(
+            "/heroes/{hero}/counters",
+            get({
+                let state = state.clone();
+                move |hero| hero_counters(hero, state)
+            }),
+        );
+    let address = format!("{}:{}", args.addr, args.port);
     let listener = tokio::net::TcpListener::bind(address).await?;
 
-    axum::serve(listener, app).await?;
+    // stop accepting connections on shutdown and let in-flight queries finish
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+        })
+        .await?;
     Ok(())
 }
 
-async fn collect(database: Arc<Database>, args: Args) -> anyhow::Result<()> {
-    let interval = Duration::from_millis(args.interval);
-    let mut sche =
-        Scheduler::new(&args.key, database, &args.collected, args.batch, interval).await?;
+async fn collect(
+    database: Arc<dyn DraftStore>,
+    args: CollectArgs,
+    shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let rate = RateController::new(
+        Duration::from_millis(args.interval),
+        Duration::from_millis(args.min_interval),
+        Duration::from_millis(args.max_interval),
+    );
+    let keys = args.keys()?;
+    let mut sche = Scheduler::new(
+        &keys,
+        database,
+        args.collected.as_deref(),
+        args.batch,
+        rate,
+        args.past_collectors,
+        args.ingest_options(),
+    )
+    .await?;
+
+    sche.run(shutdown).await
+}
 
-    sche.run().await
+// the receiver turns true on SIGINT or SIGTERM
+fn watch_shutdown() -> watch::Receiver<bool> {
+    let (notify, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        if let Err(err) = shutdown_signal().await {
+            log::error!("Failed to listen for shutdown signal: {}", err);
+            // dropping the sender would be taken as a shutdown by receivers
+            std::future::pending::<()>().await;
+        }
+        log::info!("Shutting down, waiting for in-flight work to finish");
+        let _ = notify.send(true);
+    });
+    shutdown
 }
 
 #[tokio::main]
@@ -44,27 +205,71 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    let database = Database::new(
-        &args.clickhouse_server,
-        &args.clickhouse_database,
-        args.clickhouse_user.as_deref(),
-        args.clickhouse_password.as_deref(),
-    )
-    .await?;
+    let database: Arc<dyn DraftStore> = match args.storage {
+        Storage::Clickhouse => {
+            let database = Database::new(
+                &args.clickhouse_server,
+                &args.clickhouse_database,
+                args.clickhouse_user.as_deref(),
+                args.clickhouse_password.as_deref(),
+            )
+            .await?;
+            Arc::new(database)
+        }
+        Storage::Memory => Arc::new(MemoryStore::new()),
+    };
 
-    let database = Arc::new(database);
+    let heroes = match &args.heroes {
+        Some(path) => HeroRegistry::load(path)?,
+        None => HeroRegistry::embedded(),
+    };
+    let heroes = Arc::new(heroes);
+    let patches = match &args.patches {
+        Some(path) => PatchList::load(path)?,
+        None => PatchList::embedded(),
+    };
+    let patches = Arc::new(patches);
+    let state = AppState::new(
+        database.clone(),
+        heroes.clone(),
+        args.heroes,
+        patches.clone(),
+        args.patches,
+    );
 
-    let address = format!("{}:{}", args.addr, args.port);
-    let serve = tokio::spawn(serve(database.clone(), address));
-    let collect = tokio::spawn(collect(database.clone(), args));
+    match args.command {
+        Command::Run {
+            serve: serve_args,
+            collect: collect_args,
+        } => {
+            let shutdown = watch_shutdown();
+            let mut serve = tokio::spawn(serve(state, serve_args, shutdown.clone()));
+            let mut collect = tokio::spawn(collect(database, collect_args, shutdown));
 
-    // ideally this select should never end
-    tokio::select! {
-        val = collect => {
-            val
-        },
-        val = serve => {
-            val
+            // return early on error, otherwise both tasks only end on shutdown
+            tokio::select! {
+                val = &mut collect => {
+                    val??;
+                    serve.await?
+                },
+                val = &mut serve => {
+                    val??;
+                    collect.await?
+                }
+            }
+        }
+        Command::Serve(serve_args) => {
+            let shutdown = watch_shutdown();
+            serve(state, serve_args, shutdown).await
         }
-    }?
+        Command::Collect(args) => collect(database, args, watch_shutdown()).await,
+        Command::Query(args) => commands::query(database.as_ref(), &heroes, &patches, args).await,
+        Command::Player(args) => commands::player(database.as_ref(), &heroes, args).await,
+        Command::Export(args) => commands::export(database.as_ref(), args).await,
+        Command::Import(args) => commands::import(database.as_ref(), args).await,
+        Command::Verify(args) => commands::verify(database.as_ref(), args).await,
+        Command::Stats => commands::stats(database.as_ref()).await,
+        Command::Train(args) => commands::train(database.as_ref(), args).await,
+        Command::BackfillPairs => commands::backfill_pairs(database.as_ref()).await,
+    }
 }
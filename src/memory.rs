@@ -0,0 +1,468 @@
+use std::{collections::BTreeMap, sync::RwLock};
+
+use async_trait::async_trait;
+use itertools::Itertools;
+
+use crate::{
+    dota2::{
+        HeroPairStats, HeroStats, MatchDraft, MatchPlayer, MatchupStats, PlayerMatch, Progress,
+        RejectedCount, RejectedMatch, StoreSummary,
+    },
+    filter::{DraftFilter, Side},
+    store::DraftStore,
+};
+
+// a store keeping everything in memory, nothing survives a restart
+// mostly useful for local development without a clickhouse server
+#[derive(Default)]
+pub struct MemoryStore {
+    drafts: RwLock<BTreeMap<u64, MatchDraft>>,
+    players: RwLock<Vec<MatchPlayer>>,
+    progress: RwLock<Vec<Progress>>,
+    rejected: RwLock<Vec<RejectedMatch>>,
+    collected: RwLock<Option<Vec<(u64, u64)>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // others are the allies or enemies of hero in a draft, as (others, hero won)
+    fn pair_stats<F>(&self, hero: u8, others: F) -> Vec<HeroPairStats>
+    where
+        F: Fn(&MatchDraft) -> Option<([u8; 5], bool)>,
+    {
+        let drafts = self.drafts.read().unwrap();
+        let mut pairs = BTreeMap::<u8, HeroPairStats>::new();
+        let with_outcome = drafts.values().filter(|draft| draft.has_outcome());
+        for (others, won) in with_outcome.filter_map(others) {
+            for other in others.into_iter().filter(|&other| other != hero) {
+                let stats = pairs.entry(other).or_default();
+                stats.other = other;
+                stats.games += 1;
+                stats.wins += u64::from(won);
+                stats.win_rate = stats.wins as f64 / stats.games as f64;
+            }
+        }
+        pairs
+            .into_values()
+            .sorted_by_key(|stats| std::cmp::Reverse(stats.games))
+            .collect()
+    }
+
+    // None if the draft does not match or has no outcome, same as clickhouse team1 is
+    // considered radiant if both sides match
+    fn team1_win(filter: &DraftFilter, draft: &MatchDraft) -> Option<bool> {
+        if !draft.has_outcome() {
+            return None;
+        }
+        match filter.sides(draft) {
+            (true, _) => Some(draft.radiant_win),
+            (false, true) => Some(!draft.radiant_win),
+            (false, false) => None,
+        }
+    }
+}
+
+#[async_trait]
+impl DraftStore for MemoryStore {
+    async fn query_matches(
+        &self,
+        filter: &DraftFilter,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<MatchDraft>> {
+        if filter.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let drafts = self.drafts.read().unwrap();
+        let result = drafts
+            .values()
+            .rev()
+            .filter(|draft| {
+                let (radiant, dire) = filter.sides(draft);
+                radiant || dire
+            })
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(result)
+    }
+
+    async fn query_stats(&self, filter: &DraftFilter) -> anyhow::Result<MatchupStats> {
+        if filter.is_empty() {
+            return Ok(MatchupStats::default());
+        }
+
+        let drafts = self.drafts.read().unwrap();
+        let mut stats = MatchupStats::default();
+        for draft in drafts.values() {
+            if let Some(team1_win) = Self::team1_win(filter, draft) {
+                stats.add(team1_win);
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn query_stats_by_period(
+        &self,
+        filter: &DraftFilter,
+        boundaries: &[u64],
+    ) -> anyhow::Result<Vec<(usize, MatchupStats)>> {
+        if filter.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let drafts = self.drafts.read().unwrap();
+        let mut periods = BTreeMap::<usize, MatchupStats>::new();
+        for draft in drafts.values() {
+            if let Some(team1_win) = Self::team1_win(filter, draft) {
+                let period = boundaries.partition_point(|&start| start <= draft.start_time);
+                periods.entry(period).or_default().add(team1_win);
+            }
+        }
+        Ok(periods.into_iter().collect())
+    }
+
+    async fn candidate_stats(&self, filter: &DraftFilter) -> anyhow::Result<Vec<HeroStats>> {
+        if filter.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let drafts = self.drafts.read().unwrap();
+        let mut candidates = BTreeMap::<u8, HeroStats>::new();
+        for draft in drafts.values() {
+            let Some(team1_win) = Self::team1_win(filter, draft) else {
+                continue;
+            };
+            let team1 = match filter.team1_side(draft) {
+                Side::Dire => draft.dire,
+                _ => draft.radiant,
+            };
+            for hero in team1
+                .into_iter()
+                .filter(|hero| !filter.team1.contains(hero))
+            {
+                let stats = candidates.entry(hero).or_default();
+                stats.hero = hero;
+                stats.games += 1;
+                stats.wins += u64::from(team1_win);
+            }
+        }
+        Ok(candidates.into_values().collect())
+    }
+
+    async fn hero_synergy(&self, hero: u8) -> anyhow::Result<Vec<HeroPairStats>> {
+        Ok(self.pair_stats(hero, |draft| {
+            if draft.radiant.contains(&hero) {
+                Some((draft.radiant, draft.radiant_win))
+            } else if draft.dire.contains(&hero) {
+                Some((draft.dire, !draft.radiant_win))
+            } else {
+                None
+            }
+        }))
+    }
+
+    async fn hero_counters(&self, hero: u8) -> anyhow::Result<Vec<HeroPairStats>> {
+        Ok(self.pair_stats(hero, |draft| {
+            if draft.radiant.contains(&hero) {
+                Some((draft.dire, draft.radiant_win))
+            } else if draft.dire.contains(&hero) {
+                Some((draft.radiant, !draft.radiant_win))
+            } else {
+                None
+            }
+        }))
+    }
+
+    // pairs are counted from all drafts on every query
+    async fn backfill_pairs(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn pairs_backfilled(&self) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    async fn save_match_drafts(&self, drafts: &[MatchDraft]) -> anyhow::Result<()> {
+        let mut stored = self.drafts.write().unwrap();
+        stored.extend(drafts.iter().map(|draft| (draft.match_id, draft.clone())));
+        Ok(())
+    }
+
+    async fn save_match_players(&self, players: &[MatchPlayer]) -> anyhow::Result<()> {
+        self.players.write().unwrap().extend_from_slice(players);
+        Ok(())
+    }
+
+    async fn query_player(
+        &self,
+        account_id: u32,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<PlayerMatch>> {
+        let players = self.players.read().unwrap();
+        let drafts = self.drafts.read().unwrap();
+        let result = players
+            .iter()
+            .filter(|player| player.account_id == Some(account_id))
+            .sorted_by_key(|player| std::cmp::Reverse(player.match_id))
+            .dedup_by(|a, b| a.match_id == b.match_id)
+            .filter_map(|player| {
+                let draft = drafts.get(&player.match_id)?;
+                Some(PlayerMatch {
+                    player: player.clone(),
+                    draft: draft.clone(),
+                })
+            })
+            .skip(offset)
+            .take(limit)
+            .collect();
+        Ok(result)
+    }
+
+    async fn save_progress(&self, progress: Progress) -> anyhow::Result<()> {
+        self.progress.write().unwrap().push(progress);
+        Ok(())
+    }
+
+    async fn save_rejected(&self, rejected: &[RejectedMatch]) -> anyhow::Result<()> {
+        self.rejected.write().unwrap().extend_from_slice(rejected);
+        Ok(())
+    }
+
+    async fn rejected_counts(&self) -> anyhow::Result<Vec<RejectedCount>> {
+        let rejected = self.rejected.read().unwrap();
+        let counts = rejected
+            .iter()
+            .counts_by(|rejected| rejected.reason.clone())
+            .into_iter()
+            .sorted()
+            .map(|(reason, count)| RejectedCount {
+                reason,
+                count: count as u64,
+            })
+            .collect();
+        Ok(counts)
+    }
+
+    async fn load_collected(&self) -> anyhow::Result<Option<Vec<(u64, u64)>>> {
+        Ok(self.collected.read().unwrap().clone())
+    }
+
+    async fn save_collected(&self, collected: &[(u64, u64)]) -> anyhow::Result<()> {
+        *self.collected.write().unwrap() = Some(collected.to_vec());
+        Ok(())
+    }
+
+    async fn scan_drafts(&self, after: u64, limit: usize) -> anyhow::Result<Vec<MatchDraft>> {
+        let drafts = self.drafts.read().unwrap();
+        let result = drafts
+            .range(after.saturating_add(1)..)
+            .take(limit)
+            .map(|(_, draft)| draft.clone())
+            .collect();
+        Ok(result)
+    }
+
+    async fn match_id_quantile(&self, after: u64, level: f64) -> anyhow::Result<Option<u64>> {
+        let drafts = self.drafts.read().unwrap();
+        let ids: Vec<_> = drafts
+            .range(after.saturating_add(1)..)
+            .filter(|(_, draft)| draft.has_outcome())
+            .map(|(&id, _)| id)
+            .collect();
+        let index = ((ids.len().saturating_sub(1)) as f64 * level.clamp(0.0, 1.0)) as usize;
+        Ok(ids.get(index).copied())
+    }
+
+    async fn summary(&self) -> anyhow::Result<StoreSummary> {
+        let drafts = self.drafts.read().unwrap();
+        // drafts are keyed by match id, so there are never duplicates
+        Ok(StoreSummary {
+            drafts: drafts.len() as u64,
+            matches: drafts.len() as u64,
+            min_match_id: drafts.keys().next().copied().unwrap_or_default(),
+            max_match_id: drafts.keys().next_back().copied().unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dota2::test_draft;
+
+    async fn store(drafts: &[MatchDraft]) -> MemoryStore {
+        let store = MemoryStore::new();
+        store.save_match_drafts(drafts).await.unwrap();
+        store
+    }
+
+    fn filter(team1: &[u8], team2: &[u8]) -> DraftFilter {
+        DraftFilter {
+            team1: team1.to_vec(),
+            team2: team2.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_count_team1_on_either_side() {
+        let store = store(&[
+            test_draft(1, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], true),
+            test_draft(2, [6, 7, 8, 9, 10], [1, 2, 3, 4, 5], true),
+            test_draft(3, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], false),
+        ])
+        .await;
+
+        let stats = store.query_stats(&filter(&[1], &[6])).await.unwrap();
+        assert_eq!(
+            (stats.matches, stats.team1_wins, stats.team2_wins),
+            (3, 1, 2)
+        );
+
+        // swapping teams swaps wins
+        let swapped = store.query_stats(&filter(&[6], &[1])).await.unwrap();
+        assert_eq!(
+            (swapped.matches, swapped.team1_wins, swapped.team2_wins),
+            (3, 2, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn side_restricts_team1() {
+        let store = store(&[
+            test_draft(1, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], true),
+            test_draft(2, [6, 7, 8, 9, 10], [1, 2, 3, 4, 5], true),
+        ])
+        .await;
+
+        let mut radiant = filter(&[1], &[]);
+        radiant.side = Side::Radiant;
+        let stats = store.query_stats(&radiant).await.unwrap();
+        assert_eq!((stats.matches, stats.team1_wins), (1, 1));
+
+        let mut dire = filter(&[1], &[]);
+        dire.side = Side::Dire;
+        let stats = store.query_stats(&dire).await.unwrap();
+        assert_eq!((stats.matches, stats.team1_wins), (1, 0));
+        let matches = store.query_matches(&dire, 10, 0).await.unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(dire.team1_side(&matches[0]), Side::Dire);
+    }
+
+    #[tokio::test]
+    async fn overlapping_sides_count_team1_as_radiant() {
+        let lost = test_draft(1, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], false);
+        let store = store(std::slice::from_ref(&lost)).await;
+
+        // without hero constraints on the teams both sides match
+        let filter = DraftFilter {
+            exclude_any: vec![50],
+            ..Default::default()
+        };
+        assert_eq!(filter.sides(&lost), (true, true));
+        assert_eq!(filter.team1_side(&lost), Side::Radiant);
+
+        let stats = store.query_stats(&filter).await.unwrap();
+        assert_eq!(
+            (stats.matches, stats.team1_wins, stats.team2_wins),
+            (1, 0, 1)
+        );
+        let candidates = store.candidate_stats(&filter).await.unwrap();
+        let heroes: Vec<u8> = candidates.iter().map(|stats| stats.hero).collect();
+        assert_eq!(heroes, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn exclusions_drop_matching_sides() {
+        let store = store(&[
+            test_draft(1, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], true),
+            test_draft(2, [1, 11, 12, 13, 14], [6, 7, 8, 9, 10], true),
+            test_draft(3, [6, 7, 8, 9, 10], [1, 11, 12, 13, 2], false),
+        ])
+        .await;
+
+        let mut exclude_team1 = filter(&[1], &[]);
+        exclude_team1.exclude_team1 = vec![2];
+        let stats = store.query_stats(&exclude_team1).await.unwrap();
+        assert_eq!(stats.matches, 1);
+
+        let mut exclude_team2 = filter(&[1], &[]);
+        exclude_team2.exclude_team2 = vec![7];
+        let stats = store.query_stats(&exclude_team2).await.unwrap();
+        assert_eq!(stats.matches, 0);
+
+        let mut exclude_any = filter(&[1], &[]);
+        exclude_any.exclude_any = vec![12];
+        let stats = store.query_stats(&exclude_any).await.unwrap();
+        assert_eq!((stats.matches, stats.team1_wins), (1, 1));
+        let matches = store.query_matches(&exclude_any, 10, 0).await.unwrap();
+        assert_eq!(matches[0].match_id, 1);
+    }
+
+    #[tokio::test]
+    async fn candidates_are_team1_allies() {
+        let store = store(&[
+            test_draft(1, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], true),
+            test_draft(2, [6, 7, 8, 9, 10], [1, 2, 11, 12, 13], true),
+        ])
+        .await;
+
+        let candidates = store.candidate_stats(&filter(&[1], &[])).await.unwrap();
+        let two = candidates.iter().find(|stats| stats.hero == 2).unwrap();
+        assert_eq!((two.games, two.wins), (2, 1));
+        let eleven = candidates.iter().find(|stats| stats.hero == 11).unwrap();
+        assert_eq!((eleven.games, eleven.wins), (1, 0));
+        assert!(candidates.iter().all(|stats| stats.hero != 1));
+        assert!(candidates.iter().all(|stats| stats.hero != 6));
+    }
+
+    #[tokio::test]
+    async fn drafts_without_outcome_are_not_counted() {
+        let mut legacy = test_draft(1, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], false);
+        legacy.start_time = 0;
+        let store = store(&[
+            legacy,
+            test_draft(2, [1, 2, 3, 4, 5], [6, 7, 8, 9, 10], true),
+        ])
+        .await;
+
+        let filter = filter(&[1], &[]);
+        let stats = store.query_stats(&filter).await.unwrap();
+        assert_eq!((stats.matches, stats.team1_wins), (1, 1));
+        let candidates = store.candidate_stats(&filter).await.unwrap();
+        assert!(candidates.iter().all(|stats| stats.games == 1));
+        let synergy = store.hero_synergy(1).await.unwrap();
+        assert!(synergy.iter().all(|stats| stats.games == 1));
+        // still listed
+        assert_eq!(store.query_matches(&filter, 10, 0).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn quantile_counts_drafts_not_match_ids() {
+        let teams = ([1, 2, 3, 4, 5], [6, 7, 8, 9, 10]);
+        let mut drafts: Vec<_> = (1..=10)
+            .chain(1000..1010)
+            .map(|id| test_draft(id, teams.0, teams.1, true))
+            .collect();
+        let mut legacy = test_draft(2000, teams.0, teams.1, false);
+        legacy.start_time = 0;
+        drafts.push(legacy);
+        let store = store(&drafts).await;
+
+        assert_eq!(store.match_id_quantile(0, 0.9).await.unwrap(), Some(1007));
+        assert_eq!(store.match_id_quantile(0, 1.0).await.unwrap(), Some(1009));
+        assert_eq!(store.match_id_quantile(0, 0.0).await.unwrap(), Some(1));
+        assert_eq!(
+            store.match_id_quantile(1000, 0.5).await.unwrap(),
+            Some(1005)
+        );
+        assert_eq!(store.match_id_quantile(1009, 0.5).await.unwrap(), None);
+    }
+}
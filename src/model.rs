@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dota2::MatchDraft;
+
+const HEROES: usize = 256;
+// dense weight layout: bias, heroes, ally pairs, enemy pairs
+const HERO_OFFSET: usize = 1;
+const SYNERGY_OFFSET: usize = HERO_OFFSET + HEROES;
+const COUNTER_OFFSET: usize = SYNERGY_OFFSET + HEROES * HEROES;
+const WEIGHTS: usize = COUNTER_OFFSET + HEROES * HEROES;
+
+// features are antisymmetric, swapping radiant and dire negates all of them except
+// the bias, which is the advantage of playing radiant
+fn features(radiant: &[u8; 5], dire: &[u8; 5], pairs: bool) -> Vec<(usize, f64)> {
+    let mut features = Vec::with_capacity(64);
+    features.push((0, 1.0));
+    for (team, sign) in [(radiant, 1.0), (dire, -1.0)] {
+        features.extend(team.iter().map(|&hero| (HERO_OFFSET + hero as usize, sign)));
+    }
+    if !pairs {
+        return features;
+    }
+    for (team, sign) in [(radiant, 1.0), (dire, -1.0)] {
+        for (idx, &a) in team.iter().enumerate() {
+            for &b in &team[idx + 1..] {
+                let (a, b) = (a.min(b) as usize, a.max(b) as usize);
+                features.push((SYNERGY_OFFSET + a * HEROES + b, sign));
+            }
+        }
+    }
+    for &r in radiant {
+        for &d in dire {
+            let (r, d) = (r as usize, d as usize);
+            features.push((COUNTER_OFFSET + r * HEROES + d, 1.0));
+            features.push((COUNTER_OFFSET + d * HEROES + r, -1.0));
+        }
+    }
+    features
+}
+
+fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+// how the model is saved, pair weights are sparse
+#[derive(Serialize, Deserialize)]
+struct ModelFile {
+    bias: f64,
+    // indexed by hero id
+    heroes: Vec<f64>,
+    pairs: bool,
+    // (hero, hero, weight) of allies, the smaller hero id first
+    synergy: Vec<(u8, u8, f64)>,
+    // (hero, enemy, weight)
+    counters: Vec<(u8, u8, f64)>,
+}
+
+// logistic regression of the radiant win probability
+pub struct Model {
+    weights: Vec<f64>,
+    // whether hero pairs are features as well
+    pairs: bool,
+}
+
+pub struct TrainOptions {
+    pub learning_rate: f64,
+    // l2 regularization, applied to the weights touched by each sample
+    pub l2: f64,
+    pub pairs: bool,
+}
+
+// small deterministic generator for shuffling, no need for a proper rng
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for idx in (1..items.len()).rev() {
+            let other = (self.next() % (idx as u64 + 1)) as usize;
+            items.swap(idx, other);
+        }
+    }
+}
+
+// running log loss and accuracy over drafts
+#[derive(Debug, Clone, Default)]
+pub struct Evaluation {
+    loss: f64,
+    correct: u64,
+    count: u64,
+}
+
+impl Evaluation {
+    pub fn add(&mut self, model: &Model, drafts: &[MatchDraft]) {
+        for draft in drafts {
+            let p = model.predict(&draft.radiant, &draft.dire);
+            let p = p.clamp(1e-12, 1.0 - 1e-12);
+            self.loss -= if draft.radiant_win {
+                p.ln()
+            } else {
+                (1.0 - p).ln()
+            };
+            if (p >= 0.5) == draft.radiant_win {
+                self.correct += 1;
+            }
+        }
+        self.count += drafts.len() as u64;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn loss(&self) -> f64 {
+        self.loss / self.count.max(1) as f64
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        self.correct as f64 / self.count.max(1) as f64
+    }
+}
+
+// stochastic gradient descent fed page by page, so the drafts never have to fit in
+// memory at once
+pub struct Trainer {
+    model: Model,
+    options: TrainOptions,
+    rng: XorShift,
+}
+
+impl Trainer {
+    pub fn new(options: TrainOptions) -> Self {
+        Self {
+            model: Model {
+                weights: vec![0.0; WEIGHTS],
+                pairs: options.pairs,
+            },
+            options,
+            rng: XorShift(0x9e3779b97f4a7c15),
+        }
+    }
+
+    // pages come in match id order, so drafts are only shuffled within a page
+    pub fn fit(&mut self, drafts: &mut [MatchDraft]) {
+        let TrainOptions {
+            learning_rate, l2, ..
+        } = self.options;
+        self.rng.shuffle(drafts);
+        for draft in drafts.iter() {
+            let features = features(&draft.radiant, &draft.dire, self.model.pairs);
+            let p = sigmoid(self.model.score(&features));
+            let error = p - if draft.radiant_win { 1.0 } else { 0.0 };
+            for (idx, value) in features {
+                let weight = &mut self.model.weights[idx];
+                *weight -= learning_rate * (error * value + l2 * *weight);
+            }
+        }
+    }
+
+    pub fn model(&self) -> &Model {
+        &self.model
+    }
+
+    pub fn finish(self) -> Model {
+        self.model
+    }
+}
+
+impl Model {
+    fn score(&self, features: &[(usize, f64)]) -> f64 {
+        features
+            .iter()
+            .map(|&(idx, value)| self.weights[idx] * value)
+            .sum()
+    }
+
+    // probability of radiant winning
+    pub fn predict(&self, radiant: &[u8; 5], dire: &[u8; 5]) -> f64 {
+        sigmoid(self.score(&features(radiant, dire, self.pairs)))
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let pairs = |offset: usize| {
+            self.weights[offset..offset + HEROES * HEROES]
+                .iter()
+                .enumerate()
+                .filter(|(_, &weight)| weight != 0.0)
+                .map(|(idx, &weight)| ((idx / HEROES) as u8, (idx % HEROES) as u8, weight))
+                .collect()
+        };
+        let file = ModelFile {
+            bias: self.weights[0],
+            heroes: self.weights[HERO_OFFSET..SYNERGY_OFFSET].to_vec(),
+            pairs: self.pairs,
+            synergy: pairs(SYNERGY_OFFSET),
+            counters: pairs(COUNTER_OFFSET),
+        };
+        std::fs::write(path, serde_json::to_string(&file)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let file: ModelFile = serde_json::from_str(&content)?;
+        if file.heroes.len() != HEROES {
+            anyhow::bail!(
+                "model {} has {} hero weights, expected {}",
+                path,
+                file.heroes.len(),
+                HEROES
+            );
+        }
+        let mut weights = vec![0.0; WEIGHTS];
+        weights[0] = file.bias;
+        weights[HERO_OFFSET..SYNERGY_OFFSET].copy_from_slice(&file.heroes);
+        for (offset, pairs) in [
+            (SYNERGY_OFFSET, &file.synergy),
+            (COUNTER_OFFSET, &file.counters),
+        ] {
+            for &(a, b, weight) in pairs {
+                weights[offset + a as usize * HEROES + b as usize] = weight;
+            }
+        }
+        Ok(Self {
+            weights,
+            pairs: file.pairs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dota2::test_draft;
+
+    // hero 1 wins every match it plays, on either side
+    fn drafts() -> Vec<MatchDraft> {
+        (0..200)
+            .map(|idx| {
+                let strong = [1, 2, 3, 4, 5];
+                let weak = [6, 7, 8, 9, 10];
+                if idx % 2 == 0 {
+                    test_draft(idx, strong, weak, true)
+                } else {
+                    test_draft(idx, weak, strong, false)
+                }
+            })
+            .collect()
+    }
+
+    fn trainer(pairs: bool) -> Trainer {
+        Trainer::new(TrainOptions {
+            learning_rate: 0.05,
+            l2: 1e-6,
+            pairs,
+        })
+    }
+
+    #[test]
+    fn learns_a_winning_hero() {
+        let mut trainer = trainer(false);
+        let mut drafts = drafts();
+        let mut before = Evaluation::default();
+        before.add(trainer.model(), &drafts);
+        for page in drafts.chunks_mut(50) {
+            trainer.fit(page);
+        }
+        let mut after = Evaluation::default();
+        after.add(trainer.model(), &drafts);
+
+        assert_eq!(after.count(), 200);
+        assert!(after.loss() < before.loss());
+        assert_eq!(after.accuracy(), 1.0);
+        let model = trainer.finish();
+        assert!(model.predict(&[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]) > 0.9);
+        assert!(model.predict(&[6, 7, 8, 9, 10], &[1, 2, 3, 4, 5]) < 0.1);
+    }
+
+    #[test]
+    fn swapping_sides_mirrors_hero_terms() {
+        let mut trainer = trainer(true);
+        trainer.fit(&mut drafts());
+        let model = trainer.finish();
+
+        // only the radiant bias is not mirrored
+        let bias = model.weights[0];
+        let radiant = model.predict(&[1, 12, 13, 14, 15], &[6, 7, 8, 9, 10]);
+        let dire = model.predict(&[6, 7, 8, 9, 10], &[1, 12, 13, 14, 15]);
+        let logit = |p: f64| (p / (1.0 - p)).ln();
+        assert!((logit(radiant) + logit(dire) - 2.0 * bias).abs() < 1e-9);
+    }
+
+    #[test]
+    fn saved_model_predicts_the_same() {
+        let mut trainer = trainer(true);
+        trainer.fit(&mut drafts());
+        let model = trainer.finish();
+
+        let path = std::env::temp_dir().join(format!("model-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        model.save(path).unwrap();
+        let loaded = Model::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        for (radiant, dire) in [
+            ([1, 2, 3, 4, 5], [6, 7, 8, 9, 10]),
+            ([6, 2, 13, 4, 15], [1, 7, 8, 9, 10]),
+        ] {
+            assert_eq!(
+                model.predict(&radiant, &dire),
+                loaded.predict(&radiant, &dire)
+            );
+        }
+    }
+}
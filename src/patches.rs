@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+use crate::filter::TimeRef;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PatchEntry {
+    name: String,
+    // unix timestamp or YYYY-MM-DD
+    start: TimeRef,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Patch {
+    pub name: String,
+    // unix timestamp in seconds, the patch lasts until the next one starts
+    pub start: u64,
+}
+
+pub struct PatchList {
+    // sorted by start
+    patches: Vec<Patch>,
+}
+
+impl PatchList {
+    fn new(entries: Vec<PatchEntry>) -> anyhow::Result<Self> {
+        let mut patches = entries
+            .into_iter()
+            .map(|entry| {
+                Ok(Patch {
+                    start: entry.start.resolve()?,
+                    name: entry.name,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        patches.sort_by_key(|patch| patch.start);
+        if patches.is_empty() {
+            anyhow::bail!("patch list is empty");
+        }
+        for pair in patches.windows(2) {
+            if pair[0].start == pair[1].start {
+                anyhow::bail!(
+                    "patches {} and {} start at the same time",
+                    pair[0].name,
+                    pair[1].name
+                );
+            }
+        }
+        for (idx, patch) in patches.iter().enumerate() {
+            if patches[..idx].iter().any(|other| other.name == patch.name) {
+                anyhow::bail!("patch {} is listed twice", patch.name);
+            }
+        }
+        Ok(Self { patches })
+    }
+
+    // patches shipped with this binary
+    pub fn embedded() -> Self {
+        let entries = serde_json::from_str(include_str!("patches.json")).unwrap();
+        Self::new(entries).unwrap()
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::new(serde_json::from_str(&content)?)
+    }
+
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+
+    pub fn starts(&self) -> Vec<u64> {
+        self.patches.iter().map(|patch| patch.start).collect()
+    }
+
+    // start time range [start, end) of a patch, no end for the latest patch
+    pub fn range(&self, name: &str) -> anyhow::Result<(u64, Option<u64>)> {
+        let idx = self
+            .patches
+            .iter()
+            .position(|patch| patch.name == name.trim())
+            .ok_or_else(|| anyhow::anyhow!("unknown patch {}", name))?;
+        let end = self.patches.get(idx + 1).map(|patch| patch.start);
+        Ok((self.patches[idx].start, end))
+    }
+
+    // name of a period counting the patches started before it, 0 is before the first known patch
+    pub fn period_name(&self, period: usize) -> String {
+        match period.checked_sub(1) {
+            Some(idx) => self.patches[idx].name.clone(),
+            None => format!("before {}", self.patches[0].name),
+        }
+    }
+}
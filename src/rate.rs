@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// AIMD controller of the request rate of a single key, the rate grows additively while
+// requests succeed and is cut by half on every failure
+// all time dependent methods take `now` explicitly so they can be driven by a fake clock
+#[derive(Clone, Debug)]
+pub struct RateController {
+    // requests per second
+    rate: f64,
+    min_rate: f64,
+    max_rate: f64,
+    // no request should be issued before this
+    hold_until: Option<Instant>,
+}
+
+impl RateController {
+    // requests per second gained on every successful request
+    const INCREASE: f64 = 0.002;
+    const DECREASE: f64 = 0.5;
+
+    pub fn new(initial: Duration, min_interval: Duration, max_interval: Duration) -> Self {
+        let rate_of = |interval: Duration| 1.0 / interval.as_secs_f64().max(0.001);
+        let max_rate = rate_of(min_interval);
+        let min_rate = rate_of(max_interval).min(max_rate);
+        let rate = rate_of(initial).clamp(min_rate, max_rate);
+        Self {
+            rate,
+            min_rate,
+            max_rate,
+            hold_until: None,
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.rate)
+    }
+
+    // earliest time of the next request if the previous one was issued at `last`
+    pub fn next(&self, last: Instant) -> Instant {
+        let next = last + self.interval();
+        match self.hold_until {
+            Some(hold) => std::cmp::max(next, hold),
+            None => next,
+        }
+    }
+
+    pub fn succeed(&mut self) {
+        self.rate = (self.rate + Self::INCREASE).min(self.max_rate);
+    }
+
+    // retry_after comes from the server, it overrides our own interval if longer
+    pub fn fail(&mut self, now: Instant, retry_after: Option<Duration>) {
+        self.rate = (self.rate * Self::DECREASE).max(self.min_rate);
+        let wait = retry_after.map_or(self.interval(), |dur| dur.max(self.interval()));
+        self.hold_until = Some(now + wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    // far enough from the start of the monotonic clock to go back in time
+    fn clock() -> Instant {
+        Instant::now() + secs(60)
+    }
+
+    // one request per second, between 2 and 0.125 requests per second
+    fn controller() -> RateController {
+        RateController::new(secs(1), Duration::from_millis(500), secs(8))
+    }
+
+    #[test]
+    fn initial_rate_is_clamped() {
+        let fast = RateController::new(Duration::from_millis(100), secs(1), secs(8));
+        assert_eq!(fast.rate(), 1.0);
+        let slow = RateController::new(secs(60), secs(1), secs(8));
+        assert_eq!(slow.rate(), 0.125);
+    }
+
+    #[test]
+    fn success_increases_additively_up_to_max() {
+        let mut rate = controller();
+        rate.succeed();
+        assert!((rate.rate() - 1.002).abs() < 1e-9);
+
+        for _ in 0..1000 {
+            rate.succeed();
+        }
+        assert_eq!(rate.rate(), 2.0);
+        assert_eq!(rate.interval(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn failure_halves_down_to_min() {
+        let now = clock();
+        let mut rate = controller();
+        rate.fail(now, None);
+        assert_eq!(rate.rate(), 0.5);
+        rate.fail(now, None);
+        assert_eq!(rate.rate(), 0.25);
+
+        for _ in 0..10 {
+            rate.fail(now, None);
+        }
+        assert_eq!(rate.rate(), 0.125);
+        assert_eq!(rate.interval(), secs(8));
+    }
+
+    #[test]
+    fn next_waits_for_hold() {
+        let now = clock();
+        let mut rate = controller();
+        assert_eq!(rate.next(now), now + secs(1));
+
+        // halved to one request every 2 seconds, held from now on
+        rate.fail(now, None);
+        assert_eq!(rate.next(now - secs(10)), now + secs(2));
+        assert_eq!(rate.next(now + secs(5)), now + secs(7));
+
+        // the hold stays after successes, only the interval shrinks
+        rate.succeed();
+        assert_eq!(rate.next(now - secs(10)), now + secs(2));
+    }
+
+    #[test]
+    fn retry_after_overrides_shorter_interval() {
+        let now = clock();
+        let mut rate = controller();
+        rate.fail(now, Some(secs(30)));
+        assert_eq!(rate.next(now), now + secs(30));
+        assert_eq!(rate.next(now + secs(29)), now + secs(31));
+
+        // a retry_after shorter than our own interval is ignored
+        rate.fail(now, Some(Duration::from_millis(1)));
+        assert_eq!(rate.interval(), secs(4));
+        assert_eq!(rate.next(now - secs(10)), now + secs(4));
+    }
+}
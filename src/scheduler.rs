@@ -1,21 +1,34 @@
-use std::{collections::VecDeque, ops::Range, sync::Arc, time::Duration};
+use std::{
+    io::Write,
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use backon::ExponentialBuilder;
 use backon::Retryable;
 use kez::Client;
 use serde::{Deserialize, Serialize};
-use tokio::time::Instant;
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+};
 
 use crate::dota2::Progress;
 use crate::{
-    collector::{CollectResult, Collector},
-    database::Database,
-    dota2::MatchDraft,
+    collector::{CollectResult, Collected, Collector, IngestOptions},
+    keys::KeyPool,
+    rate::RateController,
+    store::{ensure_pairs_backfilled, DraftStore},
 };
 
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct CollectorState {
     collected: Vec<(u64, u64)>,
+    // ranges currently assigned to past collectors, not persisted
+    #[serde(skip)]
+    claimed: Vec<Range<u64>>,
 }
 
 pub async fn get_a_recent_match_seq_num(client: &Client) -> kez::Result<u64> {
@@ -29,11 +42,28 @@ pub async fn get_a_recent_match_seq_num(client: &Client) -> kez::Result<u64> {
 }
 
 impl CollectorState {
-    pub async fn new(path: &str, client: &Client) -> anyhow::Result<Self> {
-        let mut state = std::fs::read_to_string(path)
-            .ok()
-            .and_then(|content| serde_json::from_str::<CollectorState>(&content).ok())
-            .unwrap_or_default();
+    pub async fn new(
+        database: &dyn DraftStore,
+        path: Option<&str>,
+        client: &Client,
+    ) -> anyhow::Result<Self> {
+        let mut state = match database.load_collected().await? {
+            Some(collected) => Self {
+                collected,
+                ..Default::default()
+            },
+            // nothing in database yet, import the local file from older deployments
+            None => match path {
+                Some(path) => {
+                    let state = Self::load(path)?.unwrap_or_default();
+                    if !state.collected.is_empty() {
+                        log::info!("Importing collector state from {}", path);
+                    }
+                    state
+                }
+                None => Self::default(),
+            },
+        };
         if state.collected.is_empty() {
             let start = { || async { get_a_recent_match_seq_num(client).await } }
                 .retry(ExponentialBuilder::default())
@@ -46,15 +76,56 @@ impl CollectorState {
         Ok(state)
     }
 
-    pub fn prev_range(idx: u64) -> Option<Range<u64>> {
-        const N: u64 = 100000;
-        match idx {
-            0 => None,
-            idx => {
-                let start = (idx - 1) / N * N;
-                Some(start..idx)
+    // None if the file does not exist, a broken file is an error rather than a fresh start
+    // because starting over silently abandons the whole backfill
+    pub fn load(path: &str) -> anyhow::Result<Option<Self>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                anyhow::bail!("failed to read collector state {}: {}", path, err);
             }
+        };
+        match serde_json::from_str(&content) {
+            Ok(state) => Ok(Some(state)),
+            Err(err) => anyhow::bail!(
+                "failed to parse collector state {}: {}, restore it from {}.bak \
+                or move it away to start over from a recent match",
+                path,
+                err,
+                path
+            ),
+        }
+    }
+
+    // replace the file atomically and keep the previous generation as {path}.bak
+    fn save(&self, path: &str) -> anyhow::Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        let tmp = format!("{}.tmp", path);
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+
+        let target = Path::new(path);
+        if target.exists() {
+            let backup = format!("{}.bak", path);
+            let backup_tmp = format!("{}.bak.tmp", path);
+            std::fs::copy(target, &backup_tmp)?;
+            std::fs::rename(&backup_tmp, &backup)?;
         }
+        std::fs::rename(&tmp, target)?;
+
+        // make sure the renames reach the disk as well
+        let dir = match target.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn collected(&self) -> &[(u64, u64)] {
+        &self.collected
     }
 
     pub fn onward_range(&self) -> Range<u64> {
@@ -62,15 +133,54 @@ impl CollectorState {
         end..u64::MAX
     }
 
+    // uncollected ranges from newest to oldest, the oldest one reaches down to 0
+    fn gaps(&self) -> Vec<Range<u64>> {
+        let ends = std::iter::once(0).chain(self.collected.iter().map(|&(_, end)| end));
+        let starts = self.collected.iter().map(|&(start, _)| start);
+        let mut gaps: Vec<_> = ends
+            .zip(starts)
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| start..end)
+            .collect();
+        gaps.reverse();
+        gaps
+    }
+
+    // the newest uncollected and unclaimed range, at most N matches long
     pub fn past_range(&self) -> Option<Range<u64>> {
-        let mut iter = self.collected.iter().rev();
-        let last = iter.next();
-        let sec = iter.next();
-        match (sec, last) {
-            (None, Some(&(start, _))) => Self::prev_range(start),
-            (Some(&(_, start)), Some(&(end, _))) => Some(start..end),
-            _ => unreachable!(),
-        }
+        const N: u64 = 100000;
+        self.gaps().into_iter().find_map(|gap| {
+            let mut end = gap.end;
+            // skip claimed ranges at the top of this gap
+            while let Some(claim) = self
+                .claimed
+                .iter()
+                .find(|claim| claim.start < end && end <= claim.end)
+            {
+                end = claim.start;
+            }
+            if end <= gap.start {
+                return None;
+            }
+            let start = self
+                .claimed
+                .iter()
+                .map(|claim| claim.end)
+                .filter(|&claim_end| claim_end <= end)
+                .fold(gap.start, std::cmp::max);
+            let start = std::cmp::max(start, (end - 1) / N * N);
+            Some(start..end)
+        })
+    }
+
+    pub fn claim_past_range(&mut self) -> Option<Range<u64>> {
+        let range = self.past_range()?;
+        self.claimed.push(range.clone());
+        Some(range)
+    }
+
+    pub fn release(&mut self, range: &Range<u64>) {
+        self.claimed.retain(|claim| claim != range);
     }
 
     pub fn complete(&mut self, range: Range<u64>) {
@@ -90,139 +200,438 @@ impl CollectorState {
     }
 }
 
+// matches collected in range, a past collector releases its claimed range with its last batch
+struct Batch {
+    range: Range<u64>,
+    collected: Collected,
+    release: Option<Range<u64>>,
+}
+
+type KeyRequest = oneshot::Sender<usize>;
+
+// hands out api keys to collectors, the onward collector always goes first
+#[derive(Clone)]
+struct Limiter {
+    high: mpsc::Sender<KeyRequest>,
+    low: mpsc::Sender<KeyRequest>,
+}
+
+impl Limiter {
+    // log current request rate every this many requests
+    const REPORT_EVERY: u64 = 100;
+
+    fn new(
+        keys: Arc<KeyPool>,
+        shutdown: watch::Receiver<bool>,
+    ) -> (Self, impl std::future::Future<Output = ()>) {
+        let (high, high_rx) = mpsc::channel(1);
+        let (low, low_rx) = mpsc::channel(16);
+        let dispatch = Self::dispatch(keys, high_rx, low_rx, shutdown);
+        (Self { high, low }, dispatch)
+    }
+
+    async fn acquire(&self, onward: bool) -> anyhow::Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        let sender = if onward { &self.high } else { &self.low };
+        sender.send(tx).await?;
+        Ok(rx.await?)
+    }
+
+    // ends when every Limiter has been dropped or on shutdown, a key may be held for
+    // much longer than the grace period of a shutdown
+    async fn dispatch(
+        keys: Arc<KeyPool>,
+        mut high: mpsc::Receiver<KeyRequest>,
+        mut low: mpsc::Receiver<KeyRequest>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let mut pending = None;
+        let mut count = 0u64;
+        loop {
+            let (onward, req) = match pending.take() {
+                Some(req) => (false, req),
+                None => tokio::select! {
+                    biased;
+                    Ok(_) = shutdown.wait_for(|&shutdown| shutdown) => break,
+                    Some(req) = high.recv() => (true, req),
+                    Some(req) = low.recv() => (false, req),
+                    else => break,
+                },
+            };
+            let key = tokio::select! {
+                key = keys.acquire() => key,
+                Ok(_) = shutdown.wait_for(|&shutdown| shutdown) => break,
+            };
+            count += 1;
+            if count.is_multiple_of(Self::REPORT_EVERY) {
+                log::info!("Requesting at {:.3} requests/s", keys.rate());
+            }
+            // the onward collector may have asked while we were waiting for the key
+            let req = match onward {
+                true => req,
+                false => match high.try_recv() {
+                    Ok(high) => {
+                        pending = Some(req);
+                        high
+                    }
+                    Err(_) => req,
+                },
+            };
+            // the collector may have gone away, just drop the key in that case
+            let _ = req.send(key);
+        }
+    }
+}
+
+// everything a collector task needs
+#[derive(Clone)]
+struct Worker {
+    keys: Arc<KeyPool>,
+    ingest: Arc<IngestOptions>,
+    limiter: Limiter,
+    writer: mpsc::Sender<Batch>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Worker {
+    // delay before the onward collector asks for new matches after catching up
+    const YIELD_DELAY: Duration = Duration::from_secs(10);
+
+    // None if shutting down, the collector is left untouched in that case
+    async fn step(
+        &mut self,
+        col: &mut Collector,
+        onward: bool,
+    ) -> anyhow::Result<Option<CollectResult>> {
+        let step = async {
+            // request rate control
+            let key = self.limiter.acquire(onward).await?;
+            let result = col.step(&self.keys.get(key)).await?;
+            match result {
+                // kez does not expose response headers, so there is no Retry-After to honor
+                CollectResult::Decel => self.keys.fail(key, None),
+                // keep the rate when the api could not be reached at all
+                CollectResult::Retry => {}
+                _ => self.keys.succeed(key),
+            }
+            anyhow::Ok(result)
+        };
+        // the dispatcher stops on shutdown as well, so a failed acquire must not win
+        tokio::select! {
+            biased;
+            _ = self.shutdown.wait_for(|&shutdown| shutdown) => Ok(None),
+            result = step => result.map(Some),
+        }
+    }
+
+    async fn save(
+        &self,
+        range: Range<u64>,
+        collected: Collected,
+        release: Option<Range<u64>>,
+    ) -> anyhow::Result<()> {
+        self.writer
+            .send(Batch {
+                range,
+                collected,
+                release,
+            })
+            .await?;
+        Ok(())
+    }
+
+    // save whatever is cached so it does not need to be collected again
+    async fn flush(&self, col: &mut Collector) -> anyhow::Result<()> {
+        if let Some(CollectResult::Save(range, collected)) = col.flush() {
+            self.save(range, collected, None).await?;
+        }
+        Ok(())
+    }
+
+    async fn collect_onward(mut self, mut col: Collector) -> anyhow::Result<()> {
+        loop {
+            let Some(result) = self.step(&mut col, true).await? else {
+                return self.flush(&mut col).await;
+            };
+            match result {
+                CollectResult::Normal | CollectResult::Decel | CollectResult::Retry => {}
+                CollectResult::Yield => {
+                    // caught up with the latest matches, leave the budget to past collectors
+                    let shutdown = self.shutdown.wait_for(|&shutdown| shutdown);
+                    if tokio::time::timeout(Self::YIELD_DELAY, shutdown)
+                        .await
+                        .is_ok()
+                    {
+                        return self.flush(&mut col).await;
+                    }
+                }
+                CollectResult::Save(range, collected) => self.save(range, collected, None).await?,
+                CollectResult::Completed(range, collected) => {
+                    return self.save(range, collected, None).await;
+                }
+            }
+        }
+    }
+
+    async fn collect_past(
+        mut self,
+        state: Arc<Mutex<CollectorState>>,
+        batch: usize,
+    ) -> anyhow::Result<()> {
+        loop {
+            let claim = state.lock().unwrap().claim_past_range();
+            // None means we have finished collecting all history matches
+            let Some(claim) = claim else {
+                return Ok(());
+            };
+            let mut col = Collector::new(claim.clone(), batch, self.ingest.clone());
+            loop {
+                let Some(result) = self.step(&mut col, false).await? else {
+                    return self.flush(&mut col).await;
+                };
+                match result {
+                    CollectResult::Normal
+                    | CollectResult::Yield
+                    | CollectResult::Decel
+                    | CollectResult::Retry => {}
+                    CollectResult::Save(range, collected) => {
+                        self.save(range, collected, None).await?
+                    }
+                    CollectResult::Completed(range, collected) => {
+                        self.save(range, collected, Some(claim)).await?;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub struct Scheduler {
-    client: Client,
-    database: Arc<Database>,
+    keys: Arc<KeyPool>,
+    ingest: Arc<IngestOptions>,
+    database: Arc<dyn DraftStore>,
     batch: usize,
-    interval: Duration,
-    state_path: String,
-    state: CollectorState,
-    queue: VecDeque<(usize, Collector)>,
+    past_collectors: usize,
+    // optional local copy of the state
+    state_path: Option<String>,
+    state: Arc<Mutex<CollectorState>>,
 }
 
 impl Scheduler {
     pub async fn new(
-        key: &str,
-        database: Arc<Database>,
-        state_path: &str,
+        keys: &[String],
+        database: Arc<dyn DraftStore>,
+        state_path: Option<&str>,
         batch: usize,
-        interval: Duration,
+        rate: RateController,
+        past_collectors: usize,
+        ingest: IngestOptions,
     ) -> anyhow::Result<Self> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .connect_timeout(Duration::from_secs(30))
-            .build()?;
-        let client = Client::with_client(client, key);
-
-        let state_path = state_path.to_string();
-        let state = CollectorState::new(&state_path, &client).await?;
+        ensure_pairs_backfilled(database.as_ref()).await?;
+        let keys = Arc::new(KeyPool::new(keys, rate)?);
 
-        let range_onward = state.onward_range();
-
-        let queue = VecDeque::from([
-            (256, Collector::new(range_onward, batch)), // onward => 256
-        ]);
+        let state = CollectorState::new(database.as_ref(), state_path, &keys.client()).await?;
+        let state = Arc::new(Mutex::new(state));
 
         Ok(Self {
-            client,
+            keys,
+            ingest: Arc::new(ingest),
             database,
             batch,
-            interval,
-            queue,
-            state_path,
+            past_collectors,
+            state_path: state_path.map(ToString::to_string),
             state,
         })
     }
 
-    pub fn new_past_collector(&self) -> Option<Collector> {
-        self.state
-            .past_range()
-            .map(|range| Collector::new(range, self.batch * 10))
-    }
+    pub async fn run(&mut self, shutdown: watch::Receiver<bool>) -> anyhow::Result<()> {
+        let (limiter, dispatch) = Limiter::new(self.keys.clone(), shutdown.clone());
+        // a single writer keeps saving drafts and state in order
+        let (writer, batches) = mpsc::channel(self.past_collectors + 1);
+        let worker = Worker {
+            keys: self.keys.clone(),
+            ingest: self.ingest.clone(),
+            limiter,
+            writer,
+            shutdown,
+        };
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        // maybe we should use something like actor model
-        // add a collector for past matches if possible
-        if let Some(col) = self.new_past_collector() {
-            self.queue.push_back((3, col)); // by default, past collector runs 3 times in one iteration
-        }
+        // the writer is kept out of the task set, so it is never aborted halfway a batch
+        let writing = tokio::spawn(Self::write(
+            batches,
+            self.database.clone(),
+            self.state.clone(),
+            self.state_path.clone(),
+        ));
+        let mut tasks = JoinSet::new();
+        tasks.spawn(async move {
+            dispatch.await;
+            Ok(())
+        });
 
-        let mut base = Instant::now();
+        let range_onward = self.state.lock().unwrap().onward_range();
+        let col = Collector::new(range_onward, self.batch, self.ingest.clone());
+        tasks.spawn(worker.clone().collect_onward(col));
+        for _ in 0..self.past_collectors {
+            let state = self.state.clone();
+            tasks.spawn(worker.clone().collect_past(state, self.batch * 10));
+        }
+        // the writer and the dispatcher end once all collectors have ended
+        drop(worker);
 
-        // ideally the outer loop should never ends
-        loop {
-            let Some((count, mut col)) = self.queue.pop_front() else {
+        // ideally the onward collector never ends unless shutting down,
+        // stop collecting on the first error
+        let mut result = Ok(());
+        while let Some(joined) = tasks.join_next().await {
+            if let Err(err) = joined
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result)
+            {
+                result = Err(err);
                 break;
-            };
-
-            let mut index = 0;
-            // we need this loop return a collector back to us, so we use loop instead of for
-            let task = loop {
-                if index >= count {
-                    // collector end a loop with count times
-                    break Some((count, col));
-                }
+            }
+        }
+        // aborted collectors close the channel, the writer ends once it has saved
+        // every batch sent so far
+        tasks.shutdown().await;
+        writing.await??;
+        result
+    }
 
-                // request rate control
-                tokio::time::sleep_until(base + self.interval).await;
-                base = Instant::now();
+    async fn write(
+        mut batches: mpsc::Receiver<Batch>,
+        database: Arc<dyn DraftStore>,
+        state: Arc<Mutex<CollectorState>>,
+        state_path: Option<String>,
+    ) -> anyhow::Result<()> {
+        while let Some(Batch {
+            range,
+            collected,
+            release,
+        }) = batches.recv().await
+        {
+            log::info!("Saving matches in [{}, {})!", range.start, range.end);
+            let drafts = &collected.drafts;
+            { || async { database.save_match_drafts(drafts).await } }
+                .retry(ExponentialBuilder::default())
+                .notify(|err, dur| {
+                    log::warn!("Retrying {} after {}ms.", err, dur.as_millis());
+                })
+                .await?;
+            let players = &collected.players;
+            if !players.is_empty() {
+                { || async { database.save_match_players(players).await } }
+                    .retry(ExponentialBuilder::default())
+                    .notify(|err, dur| {
+                        log::warn!("Retrying {} after {}ms.", err, dur.as_millis());
+                    })
+                    .await?;
+            }
+            let rejected = &collected.rejected;
+            if !rejected.is_empty() {
+                log::info!("Rejected {} malformed matches", rejected.len());
+                { || async { database.save_rejected(rejected).await } }
+                    .retry(ExponentialBuilder::default())
+                    .notify(|err, dur| {
+                        log::warn!("Retrying {} after {}ms.", err, dur.as_millis());
+                    })
+                    .await?;
+            }
 
-                match col.step(&self.client).await? {
-                    CollectResult::Normal => {
-                        // in normal case, we don't need to do anything
-                    }
-                    CollectResult::Yield => {
-                        // yield, give back the original collector
-                        // maybe we could do some scheduler strategy here?
-                        break Some((count, col));
-                    }
-                    CollectResult::Decel => {
-                        base += Duration::from_secs(2);
-                    }
-                    CollectResult::Save(range, masks) => {
-                        // received some data to save
-                        self.save(range, masks).await?;
-                    }
-                    CollectResult::Completed(range, masks) => {
-                        self.save(range, masks).await?;
-                        // completed current range, try to schedule a new range
-                        // None means we have finished collecting all history matches
-                        break Some(count).zip(self.new_past_collector());
-                    }
+            let snapshot = {
+                let mut state = state.lock().unwrap();
+                state.complete(range);
+                if let Some(claim) = release {
+                    state.release(&claim);
                 }
-                index += 1;
+                state.clone()
             };
-
-            if let Some(task) = task {
-                // add task back to task queue to keep this loop running
-                self.queue.push_back(task);
+            Self::save_state(database.as_ref(), &snapshot, state_path.as_deref()).await?;
+            if let Some(&(_, match_seq_num)) = snapshot.collected.last() {
+                if let Some(progress) = Progress::new(match_seq_num) {
+                    let _ = database.save_progress(progress).await;
+                }
             }
         }
-
         Ok(())
     }
 
-    async fn save(&mut self, range: Range<u64>, masks: Vec<MatchDraft>) -> anyhow::Result<()> {
-        log::info!("Saving matches in [{}, {})!", range.start, range.end);
-        { || async { self.database.save_match_drafts(&masks).await } }
+    async fn save_state(
+        database: &dyn DraftStore,
+        state: &CollectorState,
+        state_path: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let collected = &state.collected;
+        { || async { database.save_collected(collected).await } }
             .retry(ExponentialBuilder::default())
             .notify(|err, dur| {
                 log::warn!("Retrying {} after {}ms.", err, dur.as_millis());
             })
             .await?;
-        self.state.complete(range);
-        self.save_state()?;
-        if let Some(&(_, match_seq_num)) = self.state.collected.last() {
-            if let Some(progress) = Progress::new(match_seq_num) {
-                let _ = self.database.save_progress(progress).await;
+        // the local file is only kept as a fallback copy of the database state, failing to
+        // write it must not stop collecting
+        if let Some(path) = state_path {
+            if let Err(err) = state.save(path) {
+                log::warn!(
+                    "Failed to update fallback collector state {}: {}",
+                    path,
+                    err
+                );
             }
         }
         Ok(())
     }
+}
 
-    fn save_state(&self) -> anyhow::Result<()> {
-        let content = serde_json::to_string_pretty(&self.state)?;
-        std::fs::write(&self.state_path, content)?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(collected: &[(u64, u64)]) -> CollectorState {
+        CollectorState {
+            collected: collected.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn claims_split_a_gap_without_overlap() {
+        let mut state = state(&[(0, 100), (350000, 400000)]);
+        let claims: Vec<_> = std::iter::from_fn(|| state.claim_past_range()).collect();
+        assert_eq!(
+            claims,
+            vec![300000..350000, 200000..300000, 100000..200000, 100..100000]
+        );
+        assert_eq!(state.past_range(), None);
+
+        // a released claim is handed out again, the others stay claimed
+        state.release(&claims[1]);
+        assert_eq!(state.claim_past_range(), Some(200000..300000));
+        assert_eq!(state.past_range(), None);
+    }
+
+    #[test]
+    fn completed_claim_is_merged() {
+        let mut state = state(&[(0, 100), (350000, 400000)]);
+        let first = state.claim_past_range().unwrap();
+        let second = state.claim_past_range().unwrap();
+
+        state.complete(first.clone());
+        state.release(&first);
+        assert_eq!(state.collected(), &[(0, 100), (300000, 400000)]);
+        // the gap shrank, but the second claim still covers its top
+        assert_eq!(state.past_range(), Some(100000..200000));
+
+        state.release(&second);
+        assert_eq!(state.past_range(), Some(200000..300000));
+    }
+
+    #[test]
+    fn claims_skip_collected_ranges() {
+        let mut state = state(&[(0, 10), (20, 30), (40, 50)]);
+        assert_eq!(state.claim_past_range(), Some(30..40));
+        assert_eq!(state.claim_past_range(), Some(10..20));
+        assert_eq!(state.claim_past_range(), None);
     }
 }
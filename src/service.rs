@@ -1,14 +1,66 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use axum::Json;
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    Json,
+};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use crate::{database::Database, dota2::MatchDraft};
+use crate::{
+    dota2::{HeroPairStats, MatchDraft, MatchupStats, PlayerMatch},
+    filter::{DraftFilter, Side, TimeRef},
+    heroes::{Hero, HeroPick, HeroRef, HeroRegistry},
+    model::Model,
+    patches::{Patch, PatchList},
+    store::DraftStore,
+};
+
+type Response<T> = Result<Json<T>, (StatusCode, String)>;
+
+fn bad_request(err: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, err.to_string())
+}
+
+// a failed query must not look like an empty result
+fn internal_error(err: anyhow::Error) -> (StatusCode, String) {
+    log::error!("Failed to query store: {}", err);
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct QueryParameter {
-    pub team1: Vec<u8>,
-    pub team2: Vec<u8>,
+    // heroes or {"hero": .., "facet": ..} to require a facet
+    pub team1: Vec<HeroPick>,
+    pub team2: Vec<HeroPick>,
+    // heroes team1/team2 must not have
+    #[serde(default)]
+    pub exclude_team1: Vec<HeroRef>,
+    #[serde(default)]
+    pub exclude_team2: Vec<HeroRef>,
+    // heroes not picked by either team
+    #[serde(default)]
+    pub exclude_any: Vec<HeroRef>,
+    #[serde(default)]
+    pub side: Side,
+    // start time in [since, until)
+    #[serde(default)]
+    pub since: Option<TimeRef>,
+    #[serde(default)]
+    pub until: Option<TimeRef>,
+    #[serde(default)]
+    pub min_match_id: Option<u64>,
+    #[serde(default)]
+    pub max_match_id: Option<u64>,
+    // only matches played on this patch
+    #[serde(default)]
+    pub patch: Option<String>,
+    // allowed game modes and lobby types, empty for any
+    #[serde(default)]
+    pub game_modes: Vec<u8>,
+    #[serde(default)]
+    pub lobby_types: Vec<u8>,
     #[serde(default = "default_count")]
     pub count: usize,
     #[serde(default)]
@@ -19,25 +71,454 @@ pub fn default_count() -> usize {
     10
 }
 
+impl QueryParameter {
+    pub fn filter(
+        &self,
+        heroes: &HeroRegistry,
+        patches: &PatchList,
+    ) -> anyhow::Result<DraftFilter> {
+        let (team1, team1_facets) = heroes.resolve_picks(&self.team1)?;
+        let (team2, team2_facets) = heroes.resolve_picks(&self.team2)?;
+        let mut filter = DraftFilter {
+            team1,
+            team2,
+            team1_facets,
+            team2_facets,
+            exclude_team1: heroes.resolve_all(&self.exclude_team1)?,
+            exclude_team2: heroes.resolve_all(&self.exclude_team2)?,
+            exclude_any: heroes.resolve_all(&self.exclude_any)?,
+            side: self.side,
+            since: self.since.as_ref().map(TimeRef::resolve).transpose()?,
+            until: self.until.as_ref().map(TimeRef::resolve).transpose()?,
+            min_match_id: self.min_match_id,
+            max_match_id: self.max_match_id,
+            game_modes: self.game_modes.clone(),
+            lobby_types: self.lobby_types.clone(),
+        };
+        if let Some(patch) = &self.patch {
+            let (start, end) = patches.range(patch)?;
+            filter.within(start, end);
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MatchEntry {
+    #[serde(flatten)]
+    pub draft: MatchDraft,
+    pub radiant_names: Vec<String>,
+    pub dire_names: Vec<String>,
+    pub team1_side: Side,
+}
+
+impl MatchEntry {
+    pub fn new(draft: MatchDraft, heroes: &HeroRegistry, filter: &DraftFilter) -> Self {
+        Self {
+            radiant_names: draft.radiant.iter().map(|&id| heroes.name(id)).collect(),
+            dire_names: draft.dire.iter().map(|&id| heroes.name(id)).collect(),
+            team1_side: filter.team1_side(&draft),
+            draft,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecommendParameter {
+    // picked heroes of our team and of the enemy team so far
+    #[serde(default)]
+    pub allies: Vec<HeroRef>,
+    #[serde(default)]
+    pub enemies: Vec<HeroRef>,
+    // only matches played on this patch
+    #[serde(default)]
+    pub patch: Option<String>,
+    // candidates with fewer games are left out
+    #[serde(default = "default_min_games")]
+    pub min_games: u64,
+    #[serde(default = "default_count")]
+    pub count: usize,
+}
+
+pub fn default_min_games() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Recommendation {
+    pub hero: u8,
+    pub name: String,
+    pub games: u64,
+    pub wins: u64,
+    pub win_rate: f64,
+    // 95% wilson score interval of the win rate, candidates are ranked by its lower bound
+    pub lower: f64,
+    pub upper: f64,
+}
+
+// wilson score interval, 1.96 for 95% confidence
+fn wilson_interval(wins: u64, games: u64) -> (f64, f64) {
+    const Z: f64 = 1.96;
+    if games == 0 {
+        return (0.0, 1.0);
+    }
+    let n = games as f64;
+    let p = wins as f64 / n;
+    let center = p + Z * Z / (2.0 * n);
+    let margin = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt();
+    let denominator = 1.0 + Z * Z / n;
+    (
+        (center - margin) / denominator,
+        (center + margin) / denominator,
+    )
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PredictParameter {
+    pub radiant: Vec<HeroRef>,
+    pub dire: Vec<HeroRef>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Prediction {
+    pub radiant_win_probability: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Page {
+    #[serde(default = "default_count")]
+    pub count: usize,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerEntry {
+    #[serde(flatten)]
+    pub draft: MatchDraft,
+    // hero and facet of the player
+    pub hero: u8,
+    pub hero_name: String,
+    pub facet: u8,
+    pub side: Side,
+    pub won: bool,
+    pub leaver_status: u8,
+    pub radiant_names: Vec<String>,
+    pub dire_names: Vec<String>,
+}
+
+impl PlayerEntry {
+    pub fn new(played: PlayerMatch, heroes: &HeroRegistry) -> Self {
+        Self {
+            hero: played.player.hero,
+            hero_name: heroes.name(played.player.hero),
+            facet: played.player.facet,
+            side: if played.player.is_radiant() {
+                Side::Radiant
+            } else {
+                Side::Dire
+            },
+            won: played.won(),
+            leaver_status: played.player.leaver_status,
+            radiant_names: played
+                .draft
+                .radiant
+                .iter()
+                .map(|&id| heroes.name(id))
+                .collect(),
+            dire_names: played
+                .draft
+                .dire
+                .iter()
+                .map(|&id| heroes.name(id))
+                .collect(),
+            draft: played.draft,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HeroPairEntry {
+    #[serde(flatten)]
+    pub stats: HeroPairStats,
+    pub other_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PatchStats {
+    pub patch: String,
+    #[serde(flatten)]
+    pub stats: MatchupStats,
+}
+
 pub struct AppState {
-    database: Arc<Database>,
+    database: Arc<dyn DraftStore>,
+    heroes: RwLock<Arc<HeroRegistry>>,
+    // where heroes are reloaded from, None for the embedded registry
+    heroes_path: Option<String>,
+    patches: RwLock<Arc<PatchList>>,
+    // where patches are reloaded from, None for the embedded list
+    patches_path: Option<String>,
+    model: Option<Model>,
 }
 
 impl AppState {
-    pub fn new(database: Arc<Database>) -> Self {
-        Self { database }
+    pub fn new(
+        database: Arc<dyn DraftStore>,
+        heroes: Arc<HeroRegistry>,
+        heroes_path: Option<String>,
+        patches: Arc<PatchList>,
+        patches_path: Option<String>,
+    ) -> Self {
+        Self {
+            database,
+            heroes: RwLock::new(heroes),
+            heroes_path,
+            patches: RwLock::new(patches),
+            patches_path,
+            model: None,
+        }
+    }
+
+    pub fn with_model(mut self, model: Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    pub fn heroes(&self) -> Arc<HeroRegistry> {
+        self.heroes.read().unwrap().clone()
+    }
+
+    pub fn patches(&self) -> Arc<PatchList> {
+        self.patches.read().unwrap().clone()
+    }
+
+    pub fn reload_heroes(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.heroes_path else {
+            return Ok(());
+        };
+        let heroes = HeroRegistry::load(path)?;
+        log::info!("Reloaded {} heroes from {}", heroes.heroes().len(), path);
+        *self.heroes.write().unwrap() = Arc::new(heroes);
+        Ok(())
+    }
+
+    pub fn reload_patches(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.patches_path else {
+            return Ok(());
+        };
+        let patches = PatchList::load(path)?;
+        log::info!("Reloaded {} patches from {}", patches.patches().len(), path);
+        *self.patches.write().unwrap() = Arc::new(patches);
+        Ok(())
     }
 }
 
 pub async fn find_matches(
     Json(para): Json<QueryParameter>,
     state: Arc<AppState>,
-) -> Json<Vec<MatchDraft>> {
+) -> Response<Vec<MatchEntry>> {
+    let heroes = state.heroes();
+    let filter = para
+        .filter(&heroes, &state.patches())
+        .map_err(bad_request)?;
+    let result = state
+        .database
+        .query_matches(&filter, para.count.min(100), para.offset)
+        .await
+        .map_err(internal_error)?;
+    let result = result
+        .into_iter()
+        .map(|draft| MatchEntry::new(draft, &heroes, &filter))
+        .collect();
+    Ok(Json(result))
+}
+
+pub async fn matchup_stats(
+    Json(para): Json<QueryParameter>,
+    state: Arc<AppState>,
+) -> Response<MatchupStats> {
+    let heroes = state.heroes();
+    let filter = para
+        .filter(&heroes, &state.patches())
+        .map_err(bad_request)?;
+    let result = state
+        .database
+        .query_stats(&filter)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(result))
+}
+
+pub async fn patch_stats(
+    Json(para): Json<QueryParameter>,
+    state: Arc<AppState>,
+) -> Response<Vec<PatchStats>> {
+    let patches = state.patches();
+    let filter = para
+        .filter(&state.heroes(), &patches)
+        .map_err(bad_request)?;
+    let result = state
+        .database
+        .query_stats_by_period(&filter, &patches.starts())
+        .await
+        .map_err(internal_error)?;
+    let result = result
+        .into_iter()
+        .map(|(period, stats)| PatchStats {
+            patch: patches.period_name(period),
+            stats,
+        })
+        .collect();
+    Ok(Json(result))
+}
+
+pub async fn player_matches(
+    Path(account_id): Path<u32>,
+    Query(page): Query<Page>,
+    state: Arc<AppState>,
+) -> Response<Vec<PlayerEntry>> {
+    let heroes = state.heroes();
     let result = state
         .database
-        .query_matches(&para.team1, &para.team2, para.count.min(100), para.offset)
+        .query_player(account_id, page.count.min(100), page.offset)
         .await
-        .ok()
-        .unwrap_or_default();
-    Json(result)
+        .map_err(internal_error)?;
+    let result = result
+        .into_iter()
+        .map(|played| PlayerEntry::new(played, &heroes))
+        .collect();
+    Ok(Json(result))
+}
+
+pub async fn recommend(
+    Json(para): Json<RecommendParameter>,
+    state: Arc<AppState>,
+) -> Response<Vec<Recommendation>> {
+    let heroes = state.heroes();
+    let allies = heroes.resolve_all(&para.allies).map_err(bad_request)?;
+    let enemies = heroes.resolve_all(&para.enemies).map_err(bad_request)?;
+    if allies.len() > 4 || enemies.len() > 5 {
+        return Err(bad_request(anyhow::anyhow!(
+            "at most 4 allies and 5 enemies are allowed"
+        )));
+    }
+    if allies.is_empty() && enemies.is_empty() {
+        return Err(bad_request(anyhow::anyhow!("no hero picked yet")));
+    }
+    if let Some(hero) = allies.iter().find(|hero| enemies.contains(hero)) {
+        return Err(bad_request(anyhow::anyhow!(
+            "{} is both an ally and an enemy",
+            heroes.name(*hero)
+        )));
+    }
+
+    let mut filter = DraftFilter {
+        team1: allies,
+        team2: enemies,
+        ..Default::default()
+    };
+    if let Some(patch) = &para.patch {
+        let (start, end) = state.patches().range(patch).map_err(bad_request)?;
+        filter.within(start, end);
+    }
+    let candidates = state
+        .database
+        .candidate_stats(&filter)
+        .await
+        .map_err(internal_error)?;
+    let result = candidates
+        .into_iter()
+        .filter(|stats| stats.games >= para.min_games.max(1))
+        .map(|stats| {
+            let (lower, upper) = wilson_interval(stats.wins, stats.games);
+            Recommendation {
+                hero: stats.hero,
+                name: heroes.name(stats.hero),
+                games: stats.games,
+                wins: stats.wins,
+                win_rate: stats.wins as f64 / stats.games as f64,
+                lower,
+                upper,
+            }
+        })
+        .sorted_by(|a, b| b.lower.total_cmp(&a.lower))
+        .take(para.count.min(100))
+        .collect();
+    Ok(Json(result))
+}
+
+pub async fn predict(
+    Json(para): Json<PredictParameter>,
+    state: Arc<AppState>,
+) -> Response<Prediction> {
+    let Some(model) = &state.model else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "no model loaded, start the server with --model".to_string(),
+        ));
+    };
+    let heroes = state.heroes();
+    let team = |team: &[HeroRef]| -> anyhow::Result<[u8; 5]> {
+        let team = heroes.resolve_all(team)?;
+        team.as_slice()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("a team needs exactly 5 heroes, got {}", team.len()))
+    };
+    let radiant = team(&para.radiant).map_err(bad_request)?;
+    let dire = team(&para.dire).map_err(bad_request)?;
+    if !radiant.iter().chain(&dire).all_unique() {
+        return Err(bad_request(anyhow::anyhow!(
+            "a hero is picked more than once"
+        )));
+    }
+    Ok(Json(Prediction {
+        radiant_win_probability: model.predict(&radiant, &dire),
+    }))
+}
+
+fn pair_entries(heroes: &HeroRegistry, stats: Vec<HeroPairStats>) -> Vec<HeroPairEntry> {
+    stats
+        .into_iter()
+        .map(|stats| HeroPairEntry {
+            other_name: heroes.name(stats.other),
+            stats,
+        })
+        .collect()
+}
+
+pub async fn hero_synergy(
+    Path(hero): Path<String>,
+    state: Arc<AppState>,
+) -> Response<Vec<HeroPairEntry>> {
+    let heroes = state.heroes();
+    let hero = heroes.resolve(&HeroRef::Name(hero)).map_err(bad_request)?;
+    let result = state
+        .database
+        .hero_synergy(hero)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(pair_entries(&heroes, result)))
+}
+
+pub async fn hero_counters(
+    Path(hero): Path<String>,
+    state: Arc<AppState>,
+) -> Response<Vec<HeroPairEntry>> {
+    let heroes = state.heroes();
+    let hero = heroes.resolve(&HeroRef::Name(hero)).map_err(bad_request)?;
+    let result = state
+        .database
+        .hero_counters(hero)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(pair_entries(&heroes, result)))
+}
+
+pub async fn list_patches(state: Arc<AppState>) -> Json<Vec<Patch>> {
+    Json(state.patches().patches().to_vec())
+}
+
+pub async fn list_heroes(state: Arc<AppState>) -> Json<Vec<Hero>> {
+    Json(state.heroes().heroes().to_vec())
 }
@@ -0,0 +1,87 @@
+use async_trait::async_trait;
+
+use crate::{
+    dota2::{
+        HeroPairStats, HeroStats, MatchDraft, MatchPlayer, MatchupStats, PlayerMatch, Progress,
+        RejectedCount, RejectedMatch, StoreSummary,
+    },
+    filter::DraftFilter,
+};
+
+// storage backend used by both the collector and the service
+#[async_trait]
+pub trait DraftStore: Send + Sync {
+    async fn query_matches(
+        &self,
+        filter: &DraftFilter,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<MatchDraft>>;
+
+    async fn query_stats(&self, filter: &DraftFilter) -> anyhow::Result<MatchupStats>;
+
+    // stats grouped by period, the number of sorted boundaries not after the start time
+    // of a match, periods without matches are left out
+    async fn query_stats_by_period(
+        &self,
+        filter: &DraftFilter,
+        boundaries: &[u64],
+    ) -> anyhow::Result<Vec<(usize, MatchupStats)>>;
+
+    // stats of every other hero on the side of team1 in matching drafts, wins are team1 wins
+    async fn candidate_stats(&self, filter: &DraftFilter) -> anyhow::Result<Vec<HeroStats>>;
+
+    // stats of hero with every ally / enemy, most played first
+    async fn hero_synergy(&self, hero: u8) -> anyhow::Result<Vec<HeroPairStats>>;
+
+    async fn hero_counters(&self, hero: u8) -> anyhow::Result<Vec<HeroPairStats>>;
+
+    // count drafts stored before hero pairs were maintained, only needed once
+    async fn backfill_pairs(&self) -> anyhow::Result<()>;
+
+    // false until drafts stored before hero pairs were maintained are counted, new
+    // drafts must not be stored before that or they would be counted twice
+    async fn pairs_backfilled(&self) -> anyhow::Result<bool>;
+
+    async fn save_match_drafts(&self, drafts: &[MatchDraft]) -> anyhow::Result<()>;
+
+    async fn save_match_players(&self, players: &[MatchPlayer]) -> anyhow::Result<()>;
+
+    // drafts an account played in, latest first, only known for matches collected
+    // with players stored
+    async fn query_player(
+        &self,
+        account_id: u32,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<PlayerMatch>>;
+
+    async fn save_progress(&self, progress: Progress) -> anyhow::Result<()>;
+
+    async fn save_rejected(&self, rejected: &[RejectedMatch]) -> anyhow::Result<()>;
+
+    // number of rejected matches by reason
+    async fn rejected_counts(&self) -> anyhow::Result<Vec<RejectedCount>>;
+
+    // latest snapshot of collected match_seq_num ranges, None if nothing was saved yet
+    async fn load_collected(&self) -> anyhow::Result<Option<Vec<(u64, u64)>>>;
+
+    async fn save_collected(&self, collected: &[(u64, u64)]) -> anyhow::Result<()>;
+
+    // drafts with match id larger than after in ascending order, used for paging through everything
+    async fn scan_drafts(&self, after: u64, limit: usize) -> anyhow::Result<Vec<MatchDraft>>;
+
+    // match id not exceeded by the given fraction of drafts with outcome and a larger match
+    // id than after, may be approximate, None without such drafts
+    async fn match_id_quantile(&self, after: u64, level: f64) -> anyhow::Result<Option<u64>>;
+
+    async fn summary(&self) -> anyhow::Result<StoreSummary>;
+}
+
+// refuse to store drafts while hero pairs still wait for their backfill
+pub async fn ensure_pairs_backfilled(database: &dyn DraftStore) -> anyhow::Result<()> {
+    if !database.pairs_backfilled().await? {
+        anyhow::bail!("hero synergy and counters are not backfilled yet, run backfill-pairs first");
+    }
+    Ok(())
+}